@@ -0,0 +1,405 @@
+//! Naming, discovery, and pruning of the backups made for a watch target.
+
+use crate::{
+	codec::{self, Encoding, COMPRESSED_EXTENSIONS},
+	delta::{self, DELTA_EXTENSION},
+	encrypt::ENCRYPTED_EXTENSION,
+	preserve::Preserve,
+	retention::RetentionPolicy,
+	template::NameTemplate,
+};
+use chrono::{DateTime, Utc};
+use std::{
+	cmp::Ordering,
+	ffi::OsString,
+	fs,
+	io::{self, ErrorKind, Write},
+	path::{Path, PathBuf},
+};
+
+/// The extension of backups that are still being written.
+const TEMP_EXTENSION: &str = ".tmp";
+
+/// Where the backups of a single target are kept, and the name they're based on.
+pub struct BackupLocation {
+	pub dir: PathBuf,
+	pub name: String,
+	pub template: NameTemplate,
+	/// Whether the timestamps in backup names are in local time rather than UTC
+	pub local_time: bool,
+}
+
+/// A backup that exists on disk.
+pub struct Backup {
+	pub path: PathBuf,
+	pub timestamp: String,
+	/// The time the backup was made according to its name, if it could be parsed
+	pub time: Option<DateTime<Utc>>,
+	/// Tells apart backups that would otherwise have the same name
+	pub sequence: u32,
+	/// The hash of the backed up content, if it's known from the name or the manifest
+	pub hash: Option<String>,
+}
+
+impl BackupLocation {
+	/// The directory the backups are placed in, which may be nested inside `dir` by the template.
+	pub fn backup_dir(&self) -> PathBuf {
+		self.dir.join(self.template.dir(&self.name))
+	}
+
+	/// The path of the backup made at the given timestamp.
+	///
+	/// Backups that would otherwise have the same name (like two made in the same millisecond)
+	/// are told apart by a sequence number, which is left off for the first one.
+	pub fn backup_path(
+		&self,
+		timestamp: &str,
+		hash: &str,
+		extension: &str,
+		sequence: u32,
+	) -> PathBuf {
+		let mut path = OsString::from(
+			self.dir
+				.join(self.template.render(&self.name, timestamp, hash)),
+		);
+		if sequence > 0 {
+			path.push(format!(".{}", sequence));
+		}
+		path.push(extension);
+		path.into()
+	}
+
+	/// Writes a backup of the source file at the given timestamp, returning the path it was
+	/// written to and whether it was made as a copy-on-write clone.
+	///
+	/// The backup is written to a temporary file next to it first, and only renamed into place
+	/// once it's complete, so an interrupted backup never looks like a finished one.
+	///
+	/// Existing backups are never overwritten - if the name is already taken, the next free
+	/// sequence number is used instead.
+	///
+	/// With a base, the backup is written as a delta against it instead of with the encoding.
+	pub fn write_backup(
+		&self,
+		source: &Path,
+		timestamp: &str,
+		hash: &str,
+		encoding: Encoding,
+		base: Option<&Path>,
+		preserve: Preserve,
+	) -> io::Result<(PathBuf, bool)> {
+		let extension = match base {
+			Some(_) => String::from(DELTA_EXTENSION),
+			None => encoding.extension(),
+		};
+		for sequence in 0.. {
+			let path = self.backup_path(timestamp, hash, &extension, sequence);
+			if fs::symlink_metadata(&path).is_ok() {
+				continue;
+			}
+			let temp_path = temp_path_for(&path);
+			let written = match base {
+				Some(base) => delta::write_delta(base, source, &temp_path, preserve).map(|_| false),
+				None => encoding.write_backup(source, &temp_path, preserve),
+			};
+			let cloned = match written {
+				Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+				Ok(cloned) => cloned,
+			};
+			if let Err(e) = fs::rename(&temp_path, &path) {
+				let _ = fs::remove_file(&temp_path);
+				return Err(e);
+			}
+			return Ok((path, cloned));
+		}
+		unreachable!()
+	}
+
+	/// Makes a backup as a hardlink to an existing file with the same content, returning the path
+	/// of the new backup.
+	///
+	/// Like with [`write_backup`](Self::write_backup), existing backups are never overwritten.
+	pub fn link_backup(
+		&self,
+		existing: &Path,
+		timestamp: &str,
+		hash: &str,
+		extension: &str,
+	) -> io::Result<PathBuf> {
+		for sequence in 0.. {
+			let path = self.backup_path(timestamp, hash, extension, sequence);
+			match fs::hard_link(existing, &path) {
+				Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+				Ok(()) => return Ok(path),
+			}
+		}
+		unreachable!()
+	}
+
+	/// Finds all the existing backups belonging to this location, oldest first.
+	///
+	/// Only files following the backup naming scheme exactly are considered, so unrelated files
+	/// (including backups of other files that happen to share a prefix) are never picked up.
+	pub fn find_backups(&self) -> io::Result<Vec<Backup>> {
+		let entries = match fs::read_dir(self.backup_dir()) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+
+		let mut backups = Vec::new();
+		for entry in entries {
+			let entry = entry?;
+			if !entry.file_type()?.is_file() {
+				continue;
+			}
+			let file_name = entry.file_name();
+			let file_name = strip_compressed_extension(&file_name.to_string_lossy()).to_owned();
+			if let Some((captures, sequence)) = parse_sequenced(&file_name, |file_name| {
+				self.template.parse_file_name(Some(&self.name), file_name)
+			}) {
+				backups.push(Backup {
+					path: entry.path(),
+					time: self
+						.template
+						.timestamp_format()
+						.parse_time(&captures.timestamp, self.local_time),
+					timestamp: captures.timestamp,
+					sequence,
+					hash: captures.hash,
+				});
+			}
+		}
+		// Timestamps that don't hold a full date and time (including sequence numbers) fall back to
+		// being sorted as strings, which is still chronological for formats like the default one
+		backups.sort_by(|a, b| {
+			a.time
+				.cmp(&b.time)
+				.then_with(|| compare_timestamps(&a.timestamp, &b.timestamp))
+				.then(a.sequence.cmp(&b.sequence))
+		});
+		Ok(backups)
+	}
+
+	/// Deletes any temporary files left over from backups that were interrupted (like by a crash),
+	/// returning the deleted paths.
+	pub fn remove_temp_files(&self) -> io::Result<Vec<PathBuf>> {
+		let entries = match fs::read_dir(self.backup_dir()) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+
+		let mut removed = Vec::new();
+		for entry in entries {
+			let entry = entry?;
+			if !entry.file_type()?.is_file() {
+				continue;
+			}
+			let file_name = entry.file_name();
+			let file_name = file_name.to_string_lossy();
+			let backup_name = match file_name
+				.strip_prefix('.')
+				.and_then(|name| name.strip_suffix(TEMP_EXTENSION))
+			{
+				Some(name) => strip_compressed_extension(name),
+				None => continue,
+			};
+			if parse_sequenced(backup_name, |backup_name| {
+				self.template.parse_file_name(Some(&self.name), backup_name)
+			})
+			.is_some()
+			{
+				fs::remove_file(entry.path())?;
+				removed.push(entry.path());
+			}
+		}
+		Ok(removed)
+	}
+}
+
+/// Compares timestamps as strings, except for numbers, which can grow longer than their padding.
+fn compare_timestamps(a: &str, b: &str) -> Ordering {
+	let is_number = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+	if is_number(a) && is_number(b) {
+		a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+	} else {
+		a.cmp(b)
+	}
+}
+
+/// Replaces the file at the destination with the original contents of the backup, carrying over
+/// the chosen metadata the backup kept.
+///
+/// The contents are written to a temporary file next to the destination and renamed over it once
+/// they're complete, so the destination is never left half-written.
+pub fn restore_backup(
+	backup_path: &Path,
+	destination: &Path,
+	preserve: Preserve,
+) -> io::Result<()> {
+	let backup_metadata = fs::metadata(backup_path)?;
+	let mut backup = codec::open_backup(backup_path)?;
+	let temp_path = temp_path_for(destination);
+	let result = fs::File::create(&temp_path)
+		.and_then(|mut temp| {
+			io::copy(&mut backup, &mut temp)?;
+			temp.flush()
+		})
+		.and_then(|_| preserve.apply(&backup_metadata, &temp_path))
+		.and_then(|_| fs::rename(&temp_path, destination));
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+	result
+}
+
+/// The temporary file a backup is written to before being renamed into place, like
+/// `.notes.txt.20210102030405678.bak.tmp`.
+fn temp_path_for(path: &Path) -> PathBuf {
+	let mut file_name = OsString::from(".");
+	file_name.push(path.file_name().unwrap_or_default());
+	file_name.push(TEMP_EXTENSION);
+	path.with_file_name(file_name)
+}
+
+/// Whether the path looks like a backup made from the template (or one still being written),
+/// regardless of which file it belongs to.
+pub fn is_backup(template: &NameTemplate, path: &Path) -> bool {
+	path.file_name().is_some_and(|name| {
+		let name = name.to_string_lossy();
+		let name = name
+			.strip_prefix('.')
+			.and_then(|name| name.strip_suffix(TEMP_EXTENSION))
+			.unwrap_or(&name);
+		parse_sequenced(strip_compressed_extension(name), |file_name| {
+			template.parse_file_name(None, file_name)
+		})
+		.is_some()
+	})
+}
+
+/// Parses a backup file name that may have a sequence number on the end of it.
+fn parse_sequenced<T>(file_name: &str, parse: impl Fn(&str) -> Option<T>) -> Option<(T, u32)> {
+	if let Some(parsed) = parse(file_name) {
+		return Some((parsed, 0));
+	}
+	let (file_name, sequence) = file_name.rsplit_once('.')?;
+	let sequence = sequence.parse::<u32>().ok().filter(|&s| s > 0)?;
+	parse(file_name).map(|parsed| (parsed, sequence))
+}
+
+/// Strips the extensions added by the codec and for being encrypted (or for being a delta backup),
+/// if there are any.
+fn strip_compressed_extension(name: &str) -> &str {
+	let name = name.strip_suffix(ENCRYPTED_EXTENSION).unwrap_or(name);
+	COMPRESSED_EXTENSIONS
+		.iter()
+		.chain(&[DELTA_EXTENSION])
+		.find_map(|extension| name.strip_suffix(extension))
+		.unwrap_or(name)
+}
+
+/// Deletes a backup, first turning any delta backups based on it into full backups.
+///
+/// Delta backups that have been turned into full backups in the meantime (like by deleting the
+/// backup they were based on) are found under their new name.
+fn remove_backup(path: &Path) -> io::Result<()> {
+	let full_path;
+	let path = if delta::is_delta(path) && fs::symlink_metadata(path).is_err() {
+		full_path = delta::full_path_of(path);
+		&full_path
+	} else {
+		path
+	};
+	delta::detach_dependents(path)?;
+	fs::remove_file(path)
+}
+
+/// Deletes the oldest backups until at most `max_backups` remain, returning the deleted paths.
+pub fn prune_to_count(location: &BackupLocation, max_backups: usize) -> io::Result<Vec<PathBuf>> {
+	let backups = location.find_backups()?;
+	let excess = backups.len().saturating_sub(max_backups);
+	let mut pruned = Vec::with_capacity(excess);
+	for backup in backups.into_iter().take(excess) {
+		remove_backup(&backup.path)?;
+		pruned.push(backup.path);
+	}
+	Ok(pruned)
+}
+
+/// Deletes the backups made before the cutoff, returning the deleted paths.
+///
+/// Backups with timestamps that can't be parsed are left alone.
+pub fn prune_older_than(
+	location: &BackupLocation,
+	cutoff: DateTime<Utc>,
+	keep_newest: bool,
+) -> io::Result<Vec<PathBuf>> {
+	let mut backups = location.find_backups()?;
+	if keep_newest {
+		backups.pop();
+	}
+	let mut pruned = Vec::new();
+	for backup in backups {
+		if backup.time.is_some_and(|time| time < cutoff) {
+			remove_backup(&backup.path)?;
+			pruned.push(backup.path);
+		}
+	}
+	Ok(pruned)
+}
+
+/// Deletes the backups the retention policy doesn't keep, returning the deleted paths.
+pub fn prune_by_retention(
+	location: &BackupLocation,
+	policy: &RetentionPolicy,
+	now: DateTime<Utc>,
+) -> io::Result<Vec<PathBuf>> {
+	let backups = location.find_backups()?;
+	let times = backups.iter().map(|backup| backup.time).collect::<Vec<_>>();
+	let mut pruned = Vec::new();
+	for (backup, keep) in backups.into_iter().zip(policy.select(&times, now)) {
+		if !keep {
+			remove_backup(&backup.path)?;
+			pruned.push(backup.path);
+		}
+	}
+	Ok(pruned)
+}
+
+/// Deletes the oldest backups until their combined size is at most `max_size` bytes, returning
+/// the deleted paths.
+///
+/// The newest backup is always kept, even if it alone is bigger than `max_size` - the returned
+/// flag says whether that's the case.
+pub fn prune_to_size(location: &BackupLocation, max_size: u64) -> io::Result<(Vec<PathBuf>, bool)> {
+	let backups = location.find_backups()?;
+	let sizes = backups
+		.iter()
+		.map(|backup| fs::metadata(&backup.path).map(|m| m.len()))
+		.collect::<io::Result<Vec<_>>>()?;
+
+	let mut total = sizes.iter().sum::<u64>();
+	let mut pruned = Vec::new();
+	let newest = backups.len().saturating_sub(1);
+	for (backup, size) in backups.into_iter().zip(sizes).take(newest) {
+		if total <= max_size {
+			break;
+		}
+		remove_backup(&backup.path)?;
+		pruned.push(backup.path);
+		total -= size;
+	}
+	Ok((pruned, total > max_size))
+}
+
+/// The directory a file is in, using the current directory for bare file names.
+pub fn parent_dir(path: &Path) -> PathBuf {
+	match path.parent() {
+		Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+		_ => PathBuf::from("."),
+	}
+}
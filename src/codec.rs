@@ -0,0 +1,182 @@
+//! The different ways backups can be written out, optionally compressing and encrypting them.
+
+use crate::{
+	delta,
+	encrypt::{self, ENCRYPTED_EXTENSION},
+	preserve::Preserve,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+	fs::{self, OpenOptions},
+	io::{self, Read, Write},
+	path::Path,
+};
+
+/// The default gzip compression level.
+const GZIP_DEFAULT_LEVEL: u32 = 6;
+
+/// The default zstd compression level.
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// The extensions added on top of the backup extension by the compressing codecs.
+pub const COMPRESSED_EXTENSIONS: &[&str] = &[".gz", ".zst"];
+
+/// How a backup gets written.
+#[derive(Clone, Copy)]
+pub enum Codec {
+	/// A plain copy of the file
+	Copy,
+	/// Gzip compression, at the given level
+	Gzip(u32),
+	/// Zstd compression, at the given level
+	Zstd(i32),
+}
+
+impl Codec {
+	/// Parses a codec spec of the form `name[:level]`, like `gzip` or `zstd:19`.
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let (name, level) = match s.split_once(':') {
+			Some((name, level)) => (name, Some(level)),
+			None => (s, None),
+		};
+		let parse_level = |min: i64, max: i64| -> Result<Option<i64>, String> {
+			level
+				.map(|level| match level.parse::<i64>() {
+					Ok(v) if (min..=max).contains(&v) => Ok(v),
+					_ => Err(format!(
+						"the {} compression level must be between {} and {}",
+						name, min, max
+					)),
+				})
+				.transpose()
+		};
+
+		match name {
+			"gzip" => Ok(Codec::Gzip(
+				parse_level(0, 9)?.map_or(GZIP_DEFAULT_LEVEL, |v| v as u32),
+			)),
+			"zstd" => Ok(Codec::Zstd(
+				parse_level(1, 22)?.map_or(ZSTD_DEFAULT_LEVEL, |v| v as i32),
+			)),
+			_ => Err(String::from(
+				"must be either gzip[:level] or zstd[:level], like zstd:3",
+			)),
+		}
+	}
+
+	/// The extension added on top of the backup extension.
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Codec::Copy => "",
+			Codec::Gzip(_) => COMPRESSED_EXTENSIONS[0],
+			Codec::Zstd(_) => COMPRESSED_EXTENSIONS[1],
+		}
+	}
+
+	/// Writes everything from the source to the destination, compressed with the codec, and
+	/// returns the destination once it's all been written.
+	fn compress<W: Write>(&self, source: &mut impl Read, mut destination: W) -> io::Result<W> {
+		match *self {
+			Codec::Copy => {
+				io::copy(source, &mut destination)?;
+				Ok(destination)
+			}
+			Codec::Gzip(level) => {
+				let mut encoder = GzEncoder::new(destination, Compression::new(level));
+				io::copy(source, &mut encoder)?;
+				encoder.finish()
+			}
+			Codec::Zstd(level) => {
+				let mut encoder = zstd::Encoder::new(destination, level)?;
+				io::copy(source, &mut encoder)?;
+				encoder.finish()
+			}
+		}
+	}
+}
+
+/// How a backup gets written - compressed with the codec, then encrypted if it should be
+/// (encrypted data doesn't compress).
+#[derive(Clone, Copy)]
+pub struct Encoding {
+	pub codec: Codec,
+	pub encrypted: bool,
+}
+
+impl Encoding {
+	/// The extension added on top of the backup extension.
+	pub fn extension(&self) -> String {
+		let mut extension = String::from(self.codec.extension());
+		if self.encrypted {
+			extension.push_str(ENCRYPTED_EXTENSION);
+		}
+		extension
+	}
+
+	/// Writes a backup of the source file to the destination, carrying over the chosen metadata,
+	/// and returns whether it was made as a copy-on-write clone.
+	///
+	/// The destination must not exist yet - if it does, this fails with `ErrorKind::AlreadyExists`
+	/// rather than overwriting it.
+	pub fn write_backup(
+		&self,
+		source_path: &Path,
+		destination_path: &Path,
+		preserve: Preserve,
+	) -> io::Result<bool> {
+		let mut source = fs::File::open(source_path)?;
+		let source_metadata = source.metadata()?;
+
+		// Plain copies are made as copy-on-write clones where the filesystem supports it (like
+		// btrfs, XFS, and APFS), which is near-instant and takes up no extra space until the file
+		// changes. Otherwise the data is copied, which the standard library does in the kernel
+		// where it can (with `copy_file_range` on Linux).
+		let cloned = matches!(self.codec, Codec::Copy)
+			&& !self.encrypted
+			&& reflink_copy::reflink(source_path, destination_path).is_ok();
+		let result = if cloned {
+			Ok(())
+		} else {
+			let destination = OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(destination_path)?;
+			if self.encrypted {
+				encrypt::encrypt(destination)
+					.and_then(|encryptor| self.codec.compress(&mut source, encryptor))
+					.and_then(|encryptor| encryptor.finish()?.flush())
+			} else {
+				self.codec
+					.compress(&mut source, destination)
+					.and_then(|mut destination| destination.flush())
+			}
+		}
+		.and_then(|_| preserve.apply(&source_metadata, destination_path));
+
+		// Don't leave a partial backup behind, since it'd look just like a complete one
+		if result.is_err() {
+			let _ = fs::remove_file(destination_path);
+		}
+		result.map(|_| cloned)
+	}
+}
+
+/// Opens a backup for reading its original contents, decrypting and decompressing it based on its
+/// extension.
+pub fn open_backup(path: &Path) -> io::Result<Box<dyn Read>> {
+	let file = fs::File::open(path)?;
+	let name = path.to_string_lossy();
+	let (name, file): (&str, Box<dyn Read>) = match name.strip_suffix(ENCRYPTED_EXTENSION) {
+		Some(name) => (name, Box::new(encrypt::decrypt(file)?)),
+		None => (&name, Box::new(file)),
+	};
+	Ok(if delta::is_delta(path) {
+		Box::new(io::Cursor::new(delta::rebuild(path)?))
+	} else if name.ends_with(COMPRESSED_EXTENSIONS[0]) {
+		Box::new(GzDecoder::new(file))
+	} else if name.ends_with(COMPRESSED_EXTENSIONS[1]) {
+		Box::new(zstd::Decoder::new(file)?)
+	} else {
+		Box::new(file)
+	})
+}
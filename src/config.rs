@@ -0,0 +1,205 @@
+//! Options loaded from a TOML config file, which are named the same as the command line options.
+//!
+//! For example, this is the same as `--interval 10s --exclude '*.swp' --quiet notes.txt`:
+//!
+//! ```toml
+//! watch = ["notes.txt"]
+//! interval = "10s"
+//! exclude = ["*.swp"]
+//! quiet = true
+//! ```
+//!
+//! Files that need different options can be given as targets of their own, which take the options
+//! at the top level as defaults:
+//!
+//! ```toml
+//! interval = "10s"
+//!
+//! [[target]]
+//! watch = ["saves/*.sav"]
+//! output_dir = "save-backups"
+//!
+//! [[target]]
+//! watch = ["notes.txt"]
+//! max_backups = 20
+//! ```
+
+use serde::Deserialize;
+use std::{
+	collections::BTreeMap,
+	env::{self, VarError},
+	fs,
+	path::Path,
+};
+use toml::Value;
+
+/// The config file that's used when one isn't given, if it's in the current directory.
+pub const DEFAULT_CONFIG_FILE: &str = "watch.toml";
+
+/// What an option in the config file can be set to.
+#[derive(Clone, Copy)]
+pub enum OptionKind {
+	/// An option that's either given or not, like `quiet = true`, or given a number of times,
+	/// like `verbose = 2`
+	Flag,
+	/// An option that takes a value, like `interval = "10s"`, or a list of them for options that
+	/// can be given multiple times, like `exclude = ["*.swp", "*~"]`
+	Value,
+}
+
+/// A config file that's been loaded.
+#[derive(Deserialize)]
+pub struct ConfigFile {
+	/// The files to watch
+	#[serde(default)]
+	pub watch: Vec<String>,
+	/// Files to watch with options of their own
+	#[serde(default)]
+	pub target: Vec<Target>,
+	/// Everything else, by the name of the option it's for
+	#[serde(flatten)]
+	pub options: BTreeMap<String, Value>,
+}
+
+/// Files to watch with options of their own, on top of the ones at the top of the config file.
+#[derive(Deserialize)]
+pub struct Target {
+	/// The files to watch
+	#[serde(default)]
+	pub watch: Vec<String>,
+	/// Everything else, by the name of the option it's for
+	#[serde(flatten)]
+	pub options: BTreeMap<String, Value>,
+}
+
+impl ConfigFile {
+	pub fn load(path: &Path) -> Result<Self, String> {
+		let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+		let config_file: Self =
+			toml::from_str(&contents).map_err(|e| e.to_string().trim_end().to_owned())?;
+		if let Some(number) = config_file
+			.target
+			.iter()
+			.position(|target| target.watch.is_empty())
+		{
+			return Err(format!(
+				"target {} doesn't have any files to watch",
+				number + 1
+			));
+		}
+		Ok(config_file)
+	}
+
+	/// Turns the options into the equivalent command line arguments (not including the files to
+	/// watch), given what kind of option each name is for.
+	///
+	/// Names can be written with underscores instead of dashes, like `output_dir`.
+	pub fn to_args(
+		&self,
+		kind_of: impl Fn(&str) -> Option<OptionKind>,
+	) -> Result<Vec<String>, String> {
+		options_to_args(&self.options, kind_of)
+	}
+}
+
+impl Target {
+	/// Turns the target's own options into the equivalent command line arguments, the same way as
+	/// [`ConfigFile::to_args`].
+	pub fn to_args(
+		&self,
+		kind_of: impl Fn(&str) -> Option<OptionKind>,
+	) -> Result<Vec<String>, String> {
+		options_to_args(&self.options, kind_of)
+	}
+}
+
+fn options_to_args(
+	options: &BTreeMap<String, Value>,
+	kind_of: impl Fn(&str) -> Option<OptionKind>,
+) -> Result<Vec<String>, String> {
+	let mut args = Vec::new();
+	for (name, value) in options {
+		let option = name.replace('_', "-");
+		let kind = kind_of(&option).ok_or_else(|| format!("there's no option called {}", name))?;
+		match (kind, value) {
+			(OptionKind::Flag, Value::Boolean(given)) => {
+				if *given {
+					args.push(format!("--{}", option));
+				}
+			}
+			(OptionKind::Flag, Value::Integer(times)) if *times >= 0 => {
+				for _ in 0..*times {
+					args.push(format!("--{}", option));
+				}
+			}
+			(OptionKind::Flag, _) => return Err(format!("{} should be true or false", name)),
+			(OptionKind::Value, Value::Array(values)) => {
+				for value in values {
+					args.push(format!("--{}={}", option, option_value(name, value)?));
+				}
+			}
+			(OptionKind::Value, value) => {
+				args.push(format!("--{}={}", option, option_value(name, value)?));
+			}
+		}
+	}
+	Ok(args)
+}
+
+/// Turns the options set through environment variables into the equivalent command line
+/// arguments, given the options to look for and what kind of option each is.
+///
+/// Each option's variable is named after it, like `WATCH_OUTPUT_DIR` for `output-dir`. Options
+/// that are given or not can be set to `true` or `false` (or `1` or `0`), and `verbose` to a
+/// number of times.
+pub fn env_args<'a>(
+	options: impl IntoIterator<Item = (&'a str, OptionKind)>,
+) -> Result<Vec<String>, String> {
+	let mut args = Vec::new();
+	for (option, kind) in options {
+		let var = env_var_name(option);
+		let value = match env::var(&var) {
+			Ok(value) => value,
+			Err(VarError::NotPresent) => continue,
+			Err(VarError::NotUnicode(_)) => return Err(format!("{} isn't valid unicode", var)),
+		};
+		match kind {
+			OptionKind::Flag => {
+				let times = match value.to_ascii_lowercase().as_str() {
+					"true" | "yes" => 1,
+					"" | "false" | "no" => 0,
+					times => times
+						.parse::<u8>()
+						.map_err(|_| format!("{} should be true or false", var))?,
+				};
+				for _ in 0..times {
+					args.push(format!("--{}", option));
+				}
+			}
+			OptionKind::Value => args.push(format!("--{}={}", option, value)),
+		}
+	}
+	Ok(args)
+}
+
+/// The name of the environment variable for the option.
+///
+/// Hooks are given the hash of the backup as `WATCH_HASH`, so `hash` is `WATCH_HASH_ALGORITHM`
+/// instead.
+pub fn env_var_name(option: &str) -> String {
+	match option {
+		"hash" => String::from("WATCH_HASH_ALGORITHM"),
+		option => format!("WATCH_{}", option.replace('-', "_").to_ascii_uppercase()),
+	}
+}
+
+/// The value to give an option on the command line.
+fn option_value(name: &str, value: &Value) -> Result<String, String> {
+	match value {
+		Value::String(value) => Ok(value.clone()),
+		Value::Integer(value) => Ok(value.to_string()),
+		Value::Float(value) => Ok(value.to_string()),
+		Value::Boolean(value) => Ok(value.to_string()),
+		_ => Err(format!("{} should be a string or a number", name)),
+	}
+}
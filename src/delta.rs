@@ -0,0 +1,155 @@
+//! Backups stored as binary deltas against the backup before them, rather than as full copies.
+//!
+//! A delta backup starts with a header naming the backup it's based on (which is always in the
+//! same directory), followed by a bsdiff patch. Its contents are rebuilt by rebuilding the base
+//! and applying the patch to it, all the way back to the nearest full backup.
+
+use crate::{codec, preserve::Preserve};
+use qbsdiff::{Bsdiff, Bspatch};
+use std::{
+	fs,
+	io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+	path::{Path, PathBuf},
+};
+
+/// The extension added on top of the backup extension for delta backups.
+pub const DELTA_EXTENSION: &str = ".delta";
+
+/// The start of every delta backup, to tell them apart from anything else with the extension.
+const MAGIC: &[u8] = b"WATCHDELTA\n";
+
+/// Whether the backup is a delta backup, going by its name.
+pub fn is_delta(path: &Path) -> bool {
+	path.to_string_lossy().ends_with(DELTA_EXTENSION)
+}
+
+/// Writes a delta backup of the source file to the destination, based on an existing backup, and
+/// carrying over the chosen metadata.
+///
+/// Like other backups, the destination must not exist yet.
+pub fn write_delta(
+	base_path: &Path,
+	source_path: &Path,
+	destination_path: &Path,
+	preserve: Preserve,
+) -> io::Result<()> {
+	let source_metadata = fs::metadata(source_path)?;
+	let base = read_backup(base_path)?;
+	let source = fs::read(source_path)?;
+	let mut patch = Vec::new();
+	Bsdiff::new(&base, &source).compare(&mut patch)?;
+
+	let base_name = base_path
+		.file_name()
+		.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "the base has no file name"))?
+		.to_string_lossy();
+	// The base is referred to without its delta extension, so that it can be turned into a full
+	// backup later on without breaking anything
+	let base_name = base_name
+		.strip_suffix(DELTA_EXTENSION)
+		.unwrap_or(&base_name);
+	let mut destination = fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(destination_path)?;
+	let result = destination
+		.write_all(MAGIC)
+		.and_then(|_| destination.write_all(base_name.as_bytes()))
+		.and_then(|_| destination.write_all(b"\n"))
+		.and_then(|_| destination.write_all(&patch))
+		.and_then(|_| destination.flush())
+		.and_then(|_| preserve.apply(&source_metadata, destination_path));
+
+	// Don't leave a partial backup behind, since it'd look just like a complete one
+	if result.is_err() {
+		let _ = fs::remove_file(destination_path);
+	}
+	result
+}
+
+/// The name a delta backup would have as a full backup, which is the name other deltas refer to it
+/// by.
+pub fn full_path_of(path: &Path) -> PathBuf {
+	let name = path.to_string_lossy();
+	PathBuf::from(name.strip_suffix(DELTA_EXTENSION).unwrap_or(&name))
+}
+
+/// The backup a delta backup is based on, or `None` if it's a full backup.
+pub fn base_of(path: &Path) -> io::Result<Option<PathBuf>> {
+	if !is_delta(path) {
+		return Ok(None);
+	}
+	read_header(path, &mut BufReader::new(fs::File::open(path)?)).map(Some)
+}
+
+/// Rebuilds the original contents of a delta backup.
+pub fn rebuild(path: &Path) -> io::Result<Vec<u8>> {
+	let mut reader = BufReader::new(fs::File::open(path)?);
+	let base_path = read_header(path, &mut reader)?;
+	let mut patch = Vec::new();
+	reader.read_to_end(&mut patch)?;
+	let base = read_backup(&base_path)?;
+	let mut rebuilt = Vec::new();
+	Bspatch::new(&patch)?.apply(&base, &mut rebuilt)?;
+	Ok(rebuilt)
+}
+
+/// Turns any delta backups based on the given backup into full backups, so that it can be
+/// deleted without them becoming impossible to rebuild.
+pub fn detach_dependents(path: &Path) -> io::Result<()> {
+	let dir = match path.parent() {
+		Some(dir) => dir,
+		None => return Ok(()),
+	};
+	for entry in fs::read_dir(dir)? {
+		let dependent = entry?.path();
+		if dependent == path || base_of(&dependent).ok().flatten().as_deref() != Some(path) {
+			continue;
+		}
+
+		let full_path = full_path_of(&dependent);
+		let metadata = fs::metadata(&dependent)?;
+		fs::write(&full_path, rebuild(&dependent)?)?;
+		Preserve::All.apply(&metadata, &full_path)?;
+		fs::remove_file(&dependent)?;
+	}
+	Ok(())
+}
+
+/// Reads the original contents of any kind of backup.
+fn read_backup(path: &Path) -> io::Result<Vec<u8>> {
+	let mut contents = Vec::new();
+	codec::open_backup(path)?.read_to_end(&mut contents)?;
+	Ok(contents)
+}
+
+/// Reads the header of a delta backup, returning the path of its base and leaving the reader at
+/// the start of the patch.
+fn read_header(path: &Path, reader: &mut impl BufRead) -> io::Result<PathBuf> {
+	let invalid = || {
+		io::Error::new(
+			ErrorKind::InvalidData,
+			format!("{} isn't a valid delta backup", path.display()),
+		)
+	};
+	let mut magic = [0; MAGIC.len()];
+	reader.read_exact(&mut magic).map_err(|_| invalid())?;
+	if magic != MAGIC {
+		return Err(invalid());
+	}
+	let mut name = Vec::new();
+	reader.read_until(b'\n', &mut name)?;
+	if name.pop() != Some(b'\n') {
+		return Err(invalid());
+	}
+	let name = String::from_utf8(name).map_err(|_| invalid())?;
+
+	// The base may have been a delta itself, or been turned into a full backup since
+	let base = path.with_file_name(&name);
+	let delta_base = path.with_file_name(format!("{}{}", name, DELTA_EXTENSION));
+	if fs::symlink_metadata(&base).is_err() && fs::symlink_metadata(&delta_base).is_ok() {
+		Ok(delta_base)
+	} else {
+		Ok(base)
+	}
+}
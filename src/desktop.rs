@@ -0,0 +1,61 @@
+//! Desktop notifications, for when the watcher's output is out of sight.
+
+use notify_rust::Notification;
+#[cfg(all(unix, not(target_os = "macos")))]
+use notify_rust::Urgency;
+use std::path::Path;
+
+/// Shows desktop notifications for backups and errors.
+///
+/// If a notification can't be shown (like when there's no notification daemon running over SSH),
+/// that's reported once and no more notifications are attempted.
+pub struct DesktopNotifier {
+	available: bool,
+}
+
+impl DesktopNotifier {
+	pub fn new() -> Self {
+		Self { available: true }
+	}
+
+	/// Lets the user know that a backup was made.
+	pub fn backup(&mut self, watch_file: &str, backup_file: &Path) {
+		let backup_name = backup_file.file_name().map_or_else(
+			|| backup_file.to_string_lossy(),
+			|name| name.to_string_lossy(),
+		);
+		self.show(
+			Notification::new()
+				.summary("Backup created")
+				.body(&format!("{} → {}", watch_file, backup_name)),
+		);
+	}
+
+	/// Warns the user about an error.
+	pub fn error(&mut self, message: &str) {
+		let mut notification = Notification::new();
+		notification.summary("Watch error").body(message);
+		#[cfg(all(unix, not(target_os = "macos")))]
+		notification.urgency(Urgency::Critical);
+		self.show(&mut notification);
+	}
+
+	fn show(&mut self, notification: &mut Notification) {
+		if !self.available {
+			return;
+		}
+		if let Err(e) = notification.appname("watch").show() {
+			eprintln!(
+				"Unable to show desktop notifications, so there won't be any more: {}",
+				e
+			);
+			self.available = false;
+		}
+	}
+}
+
+impl Default for DesktopNotifier {
+	fn default() -> Self {
+		Self::new()
+	}
+}
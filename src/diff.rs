@@ -0,0 +1,41 @@
+//! Showing what changed between two versions of a file.
+
+use crate::codec;
+use similar::TextDiff;
+use std::{
+	io::{self, Read},
+	path::Path,
+};
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Makes a unified diff between the original contents of two backups, or returns `None` if
+/// either of them isn't text.
+pub fn diff_backups(old_path: &Path, new_path: &Path) -> io::Result<Option<String>> {
+	let old = read_text(old_path)?;
+	let new = read_text(new_path)?;
+	let (old, new) = match (old, new) {
+		(Some(old), Some(new)) => (old, new),
+		_ => return Ok(None),
+	};
+	Ok(Some(
+		TextDiff::from_lines(&old, &new)
+			.unified_diff()
+			.context_radius(CONTEXT_LINES)
+			.header(&old_path.to_string_lossy(), &new_path.to_string_lossy())
+			.to_string(),
+	))
+}
+
+/// Reads the original contents of a backup, if they're UTF-8 text.
+///
+/// Contents with NUL bytes are taken to be binary, even though they're technically valid UTF-8.
+fn read_text(path: &Path) -> io::Result<Option<String>> {
+	let mut bytes = Vec::new();
+	codec::open_backup(path)?.read_to_end(&mut bytes)?;
+	if bytes.contains(&0) {
+		return Ok(None);
+	}
+	Ok(String::from_utf8(bytes).ok())
+}
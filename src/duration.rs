@@ -0,0 +1,95 @@
+//! Parsing of human-friendly durations like `90m`, `7d`, or `1h30m`.
+
+use chrono::Duration;
+
+/// The units accepted in durations, along with how many milliseconds each one is.
+const UNITS: &[(&str, i64)] = &[
+	("ms", 1),
+	("s", 1000),
+	("m", 60 * 1000),
+	("h", 60 * 60 * 1000),
+	("d", 24 * 60 * 60 * 1000),
+	("w", 7 * 24 * 60 * 60 * 1000),
+];
+
+/// Parses a duration made up of one or more number-unit pairs, like `2h` or `1m30s`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+	let invalid = || {
+		format!(
+			"invalid duration '{}' (expected something like 500ms, 90m, 48h, 7d, or 1h30m)",
+			s
+		)
+	};
+
+	let mut rest = s.trim();
+	if rest.is_empty() {
+		return Err(invalid());
+	}
+	let mut total: i64 = 0;
+	while !rest.is_empty() {
+		let digits_len = rest
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(rest.len());
+		if digits_len == 0 {
+			return Err(invalid());
+		}
+		let value = rest[..digits_len].parse::<i64>().map_err(|_| invalid())?;
+		rest = &rest[digits_len..];
+
+		let unit_len = rest
+			.find(|c: char| c.is_ascii_digit())
+			.unwrap_or(rest.len());
+		let millis = UNITS
+			.iter()
+			.find(|(unit, _)| *unit == &rest[..unit_len])
+			.map(|(_, millis)| *millis)
+			.ok_or_else(invalid)?;
+		rest = &rest[unit_len..];
+
+		total = value
+			.checked_mul(millis)
+			.and_then(|v| total.checked_add(v))
+			.ok_or_else(invalid)?;
+	}
+	Ok(Duration::milliseconds(total))
+}
+
+/// Parses a polling interval, which is either a duration like `5s` or `1m30s`, or a bare number of
+/// milliseconds like `5000` (as intervals used to be given).
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+	let s = s.trim();
+	let interval = if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+		s.parse::<i64>()
+			.map(Duration::milliseconds)
+			.map_err(|_| format!("invalid interval '{}' (the number is too large)", s))?
+	} else {
+		parse_duration(s)?
+	};
+	if interval <= Duration::zero() {
+		return Err(format!(
+			"the interval must be greater than 0 (got '{}', expected something like 500ms, 5s, 2m, \
+			 or 1m30s)",
+			s
+		));
+	}
+	Ok(interval)
+}
+
+/// Formats a duration in the same style it'd be given in, like `1h30m` or `2m5s`, rounded down to
+/// the second (or to the millisecond, for durations under a second).
+pub fn format_duration(duration: std::time::Duration) -> String {
+	let mut seconds = duration.as_secs();
+	if seconds == 0 {
+		return format!("{}ms", duration.as_millis());
+	}
+	let mut formatted = String::new();
+	for (unit, millis) in UNITS.iter().rev() {
+		let unit_seconds = (*millis / 1000) as u64;
+		if unit_seconds == 0 || seconds < unit_seconds {
+			continue;
+		}
+		formatted.push_str(&format!("{}{}", seconds / unit_seconds, unit));
+		seconds %= unit_seconds;
+	}
+	formatted
+}
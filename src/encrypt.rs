@@ -0,0 +1,58 @@
+//! Encrypting backups with a passphrase, in the [age](https://age-encryption.org) format so that
+//! they can also be decrypted with the `age` tool itself.
+
+use age::{
+	scrypt::Identity, secrecy::SecretString, stream::StreamWriter, DecryptError, Decryptor,
+	Encryptor,
+};
+use std::{
+	io::{self, ErrorKind, Read, Write},
+	iter,
+	sync::OnceLock,
+};
+
+/// The extension added on top of the rest of a backup's name when it's encrypted.
+pub const ENCRYPTED_EXTENSION: &str = ".age";
+
+/// The passphrase used to encrypt and decrypt backups.
+static PASSPHRASE: OnceLock<SecretString> = OnceLock::new();
+
+/// Sets the passphrase to encrypt and decrypt backups with, for as long as the program runs.
+///
+/// Only the first passphrase set is used.
+pub fn set_passphrase(passphrase: String) {
+	let _ = PASSPHRASE.set(SecretString::from(passphrase));
+}
+
+/// Wraps the destination so that everything written to it is encrypted.
+///
+/// The writer has to be finished with [`StreamWriter::finish`] for the backup to be complete.
+pub fn encrypt<W: Write>(destination: W) -> io::Result<StreamWriter<W>> {
+	Encryptor::with_user_passphrase(passphrase()?.clone()).wrap_output(destination)
+}
+
+/// Wraps the source so that what's read from it is decrypted.
+pub fn decrypt<R: Read>(source: R) -> io::Result<impl Read> {
+	let identity = Identity::new(passphrase()?.clone());
+	Decryptor::new(source)
+		.and_then(|decryptor| decryptor.decrypt(iter::once(&identity as &dyn age::Identity)))
+		.map_err(|e| match e {
+			DecryptError::DecryptionFailed
+			| DecryptError::KeyDecryptionFailed
+			| DecryptError::NoMatchingKeys => io::Error::new(
+				ErrorKind::InvalidData,
+				"unable to decrypt it, so the passphrase may be wrong",
+			),
+			DecryptError::Io(e) => e,
+			e => io::Error::new(ErrorKind::InvalidData, e),
+		})
+}
+
+fn passphrase() -> io::Result<&'static SecretString> {
+	PASSPHRASE.get().ok_or_else(|| {
+		io::Error::new(
+			ErrorKind::InvalidInput,
+			"there's no passphrase to encrypt or decrypt backups with",
+		)
+	})
+}
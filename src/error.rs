@@ -0,0 +1,237 @@
+//! The errors that can come up while watching.
+
+use std::{error::Error, fmt, io, path::PathBuf, process::ExitStatus};
+
+#[derive(Debug)]
+pub enum WatchError {
+	/// A watched file couldn't be read to hash it
+	Hash { path: String, source: io::Error },
+	/// The directory to place backups in couldn't be created
+	CreateDir { path: String, source: io::Error },
+	/// The backup itself couldn't be written
+	Copy { path: String, source: io::Error },
+	/// The backup didn't have the same content as the file, even after making it again
+	Verify { path: String, backup: PathBuf },
+	/// The existing backups couldn't be looked through
+	ReadBackups { path: String, source: io::Error },
+	/// Old backups couldn't be pruned
+	Prune { path: String, source: io::Error },
+	/// A hook command couldn't be run at all
+	Hook { command: String, source: io::Error },
+	/// A hook command ran, but exited unsuccessfully
+	HookFailed { command: String, status: ExitStatus },
+	/// A hook command ran for too long, and was killed
+	HookTimedOut { command: String },
+	/// A watched file couldn't be hashed for more checks in a row than allowed
+	GaveUp { path: String, checks: usize },
+	/// A watched file didn't exist at startup, and it was supposed to
+	Missing { path: String },
+	/// A watched file was deleted, and that's supposed to stop the watcher
+	Deleted { path: String },
+	/// A watched file is already being watched by another watcher
+	Locked { path: String, pid: Option<u32> },
+	/// There's no backup to restore from
+	NoBackup {
+		path: String,
+		timestamp: Option<String>,
+	},
+	/// A backup couldn't be restored
+	Restore { path: String, source: io::Error },
+	/// A backup couldn't be sent to the webhook
+	Webhook { url: String, message: String },
+	/// A backup couldn't be uploaded
+	Upload {
+		path: String,
+		destination: String,
+		message: String,
+	},
+	/// The log file couldn't be opened
+	OpenLog(io::Error),
+	/// An entry couldn't be added to the manifest
+	Manifest(io::Error),
+	/// The state file couldn't be loaded
+	LoadState(io::Error),
+	/// The state file couldn't be saved
+	State(io::Error),
+}
+
+impl fmt::Display for WatchError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			WatchError::Hash { path, source } => write!(f, "Unable to hash {}: {}", path, source),
+			WatchError::CreateDir { path, source } => write!(
+				f,
+				"Unable to create the backup directory for {}: {}",
+				path, source
+			),
+			WatchError::Copy { path, source } => {
+				write!(f, "Unable to copy a backup of {}: {}", path, source)
+			}
+			WatchError::Verify { path, backup } => write!(
+				f,
+				"The backup of {} ({}) didn't match it, even after making it again",
+				path,
+				backup.display()
+			),
+			WatchError::ReadBackups { path, source } => {
+				write!(
+					f,
+					"Unable to read the existing backups of {}: {}",
+					path, source
+				)
+			}
+			WatchError::Prune { path, source } => {
+				write!(f, "Unable to prune old backups of {}: {}", path, source)
+			}
+			WatchError::Hook { command, source } => {
+				write!(f, "Unable to run the command `{}`: {}", command, source)
+			}
+			WatchError::HookFailed { command, status } => {
+				write!(f, "The command `{}` failed ({})", command, status)
+			}
+			WatchError::HookTimedOut { command } => {
+				write!(f, "The command `{}` took too long and was killed", command)
+			}
+			WatchError::GaveUp { path, checks } => write!(
+				f,
+				"Unable to hash {} for {} checks in a row, giving up",
+				path, checks
+			),
+			WatchError::Missing { path } => write!(f, "{} doesn't exist", path),
+			WatchError::Deleted { path } => write!(f, "{} was deleted", path),
+			WatchError::Locked {
+				path,
+				pid: Some(pid),
+			} => write!(
+				f,
+				"{} is already being watched by another watcher (process {})",
+				path, pid
+			),
+			WatchError::Locked { path, pid: None } => {
+				write!(f, "{} is already being watched by another watcher", path)
+			}
+			WatchError::NoBackup {
+				path,
+				timestamp: None,
+			} => write!(f, "There are no backups of {} to restore from", path),
+			WatchError::NoBackup {
+				path,
+				timestamp: Some(timestamp),
+			} => write!(f, "There's no backup of {} made at {}", path, timestamp),
+			WatchError::Restore { path, source } => {
+				write!(f, "Unable to restore {}: {}", path, source)
+			}
+			WatchError::Webhook { url, message } => {
+				write!(f, "Unable to send the backup to {}: {}", url, message)
+			}
+			WatchError::Upload {
+				path,
+				destination,
+				message,
+			} => write!(
+				f,
+				"Unable to upload {} to {}: {}",
+				path, destination, message
+			),
+			WatchError::OpenLog(source) => {
+				write!(f, "Unable to open the log file: {}", source)
+			}
+			WatchError::Manifest(source) => {
+				write!(f, "Unable to add to the manifest: {}", source)
+			}
+			WatchError::LoadState(source) => {
+				write!(f, "Unable to load the state file: {}", source)
+			}
+			WatchError::State(source) => write!(f, "Unable to save the state file: {}", source),
+		}
+	}
+}
+
+impl WatchError {
+	/// A short name for the kind of error, like `hash` or `copy`.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			WatchError::Hash { .. } => "hash",
+			WatchError::CreateDir { .. } => "create_dir",
+			WatchError::Copy { .. } => "copy",
+			WatchError::Verify { .. } => "verify",
+			WatchError::ReadBackups { .. } => "read_backups",
+			WatchError::Prune { .. } => "prune",
+			WatchError::Hook { .. } => "hook",
+			WatchError::HookFailed { .. } => "hook_failed",
+			WatchError::HookTimedOut { .. } => "hook_timed_out",
+			WatchError::GaveUp { .. } => "gave_up",
+			WatchError::Missing { .. } => "missing",
+			WatchError::Deleted { .. } => "deleted",
+			WatchError::Locked { .. } => "locked",
+			WatchError::NoBackup { .. } => "no_backup",
+			WatchError::Restore { .. } => "restore",
+			WatchError::Webhook { .. } => "webhook",
+			WatchError::Upload { .. } => "upload",
+			WatchError::OpenLog(_) => "open_log",
+			WatchError::Manifest(_) => "manifest",
+			WatchError::LoadState(_) => "load_state",
+			WatchError::State(_) => "state",
+		}
+	}
+
+	/// The watched file the error is about, if it's about one in particular.
+	pub fn path(&self) -> Option<&str> {
+		match self {
+			WatchError::Hash { path, .. }
+			| WatchError::CreateDir { path, .. }
+			| WatchError::Copy { path, .. }
+			| WatchError::Verify { path, .. }
+			| WatchError::ReadBackups { path, .. }
+			| WatchError::Prune { path, .. }
+			| WatchError::GaveUp { path, .. }
+			| WatchError::Missing { path }
+			| WatchError::Deleted { path }
+			| WatchError::Locked { path, .. }
+			| WatchError::NoBackup { path, .. }
+			| WatchError::Restore { path, .. } => Some(path),
+			WatchError::Hook { .. }
+			| WatchError::HookFailed { .. }
+			| WatchError::HookTimedOut { .. }
+			| WatchError::Webhook { .. }
+			| WatchError::Upload { .. }
+			| WatchError::OpenLog(_)
+			| WatchError::Manifest(_)
+			| WatchError::LoadState(_)
+			| WatchError::State(_) => None,
+		}
+	}
+
+	/// Whether this is just a watched file not existing, like while it's being replaced on save.
+	pub fn is_missing_file(&self) -> bool {
+		matches!(self, WatchError::Hash { source, .. } if source.kind() == io::ErrorKind::NotFound)
+	}
+}
+
+impl Error for WatchError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			WatchError::Hash { source, .. }
+			| WatchError::CreateDir { source, .. }
+			| WatchError::Copy { source, .. }
+			| WatchError::ReadBackups { source, .. }
+			| WatchError::Prune { source, .. }
+			| WatchError::Hook { source, .. }
+			| WatchError::Restore { source, .. }
+			| WatchError::OpenLog(source)
+			| WatchError::Manifest(source)
+			| WatchError::LoadState(source)
+			| WatchError::State(source) => Some(source),
+			WatchError::HookFailed { .. }
+			| WatchError::HookTimedOut { .. }
+			| WatchError::Verify { .. }
+			| WatchError::Webhook { .. }
+			| WatchError::Upload { .. }
+			| WatchError::GaveUp { .. }
+			| WatchError::Missing { .. }
+			| WatchError::Deleted { .. }
+			| WatchError::Locked { .. }
+			| WatchError::NoBackup { .. } => None,
+		}
+	}
+}
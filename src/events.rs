@@ -0,0 +1,125 @@
+//! Working out what to watch for filesystem events, and which events matter.
+
+use crate::{
+	backup, exclude, lock,
+	targets::{is_glob, IgnoreFiles},
+	watcher::WatchConfig,
+};
+use notify::{Event, RecursiveMode};
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+/// Finds the directory that needs to be watched for changes to files matching the pattern.
+///
+/// The containing directories are watched rather than the files themselves, so that files which
+/// are replaced on save (or which don't exist yet) are still noticed.
+pub fn watch_dir_for(pattern: &str, recursive: bool) -> (PathBuf, RecursiveMode) {
+	let path = Path::new(pattern);
+	if path.is_dir() {
+		let recursive_mode = if recursive {
+			RecursiveMode::Recursive
+		} else {
+			RecursiveMode::NonRecursive
+		};
+		return (path.to_path_buf(), recursive_mode);
+	}
+
+	// Take the leading components up until the first one with glob syntax in it
+	let mut watch_dir = PathBuf::new();
+	let mut components = path.components().peekable();
+	while let Some(component) = components.next() {
+		if components.peek().is_none() {
+			break;
+		}
+		if is_glob(&component.as_os_str().to_string_lossy()) {
+			return (dir_or_current(watch_dir), RecursiveMode::Recursive);
+		}
+		watch_dir.push(component);
+	}
+	(dir_or_current(watch_dir), RecursiveMode::NonRecursive)
+}
+
+/// Finds the directory of the file a symlink points to, or `None` if the path isn't a symlink or
+/// it's broken.
+pub fn symlink_target_dir(path: &Path) -> Option<PathBuf> {
+	if !fs::symlink_metadata(path).ok()?.file_type().is_symlink() {
+		return None;
+	}
+	fs::canonicalize(path).ok()?.parent().map(Path::to_path_buf)
+}
+
+fn dir_or_current(dir: PathBuf) -> PathBuf {
+	if dir.as_os_str().is_empty() {
+		PathBuf::from(".")
+	} else {
+		dir
+	}
+}
+
+/// Whether an event could mean that a watched file has changed.
+///
+/// Events that only touch backup files or lock files are ignored, since they're caused by the
+/// watcher itself, and so are ones that only touch excluded files.
+pub fn is_relevant_event(config: &WatchConfig, event: &notify::Result<Event>) -> bool {
+	match event {
+		Ok(event) => {
+			(event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove())
+				&& event.paths.iter().any(|p| {
+					!backup::is_backup(&config.name_template, p)
+						&& !lock::is_lock_file(p)
+						&& !is_excluded_path(config, p)
+				})
+		}
+		Err(_) => false,
+	}
+}
+
+/// The paths the event says were removed, if it's a removal.
+///
+/// They're made absolute, so that they can be compared with any [`absolute_path`] of a target.
+pub fn removed_paths(event: &notify::Result<Event>) -> Vec<PathBuf> {
+	match event {
+		Ok(event) if event.kind.is_remove() => event
+			.paths
+			.iter()
+			.filter_map(|p| absolute_path(p))
+			.collect(),
+		_ => Vec::new(),
+	}
+}
+
+/// The path with the directory it's in resolved, which works even when the file itself is gone.
+pub fn absolute_path(path: &Path) -> Option<PathBuf> {
+	let name = path.file_name()?;
+	let dir = match path.parent() {
+		Some(dir) if dir != Path::new("") => dir,
+		_ => Path::new("."),
+	};
+	Some(fs::canonicalize(dir).ok()?.join(name))
+}
+
+/// Whether the path is skipped, in any of the directories being watched (or by its name alone, if
+/// it isn't in any of them).
+fn is_excluded_path(config: &WatchConfig, path: &Path) -> bool {
+	let is_dir = path.is_dir();
+	let mut in_watched_dir = false;
+	for pattern in &config.watch_patterns {
+		let (watch_dir, _) = watch_dir_for(pattern, config.recursive);
+		let real_dir = fs::canonicalize(&watch_dir).ok();
+		for dir in [Some(watch_dir), real_dir].iter().flatten() {
+			if !path.starts_with(dir) {
+				continue;
+			}
+			in_watched_dir = true;
+			if IgnoreFiles::new(config, dir).is_ignored_within(path, is_dir) {
+				return true;
+			}
+		}
+	}
+	!in_watched_dir && {
+		let name = path.file_name().map_or(path, Path::new);
+		exclude::is_excluded(&config.excludes, name, is_dir)
+	}
+}
@@ -0,0 +1,103 @@
+//! Patterns for files to skip, which work like the ones in a `.gitignore`.
+
+use glob::Pattern;
+use std::{fs, io, path::Path};
+
+/// A pattern for files to skip, matched against paths relative to what's being watched.
+///
+/// Like in a `.gitignore`, a pattern ending in `/` only matches directories (skipping everything
+/// in them), one starting with `/` only matches from the top of what's being watched rather than
+/// at any depth, and one starting with `!` brings back files skipped by the patterns before it.
+#[derive(Clone)]
+pub struct ExcludePattern {
+	pattern: Pattern,
+	/// Whether it only matches directories
+	directory: bool,
+	/// Whether it only matches the whole relative path, rather than just the name
+	anchored: bool,
+	/// Whether it brings back files instead of skipping them
+	negated: bool,
+}
+
+impl ExcludePattern {
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let (negated, s) = match s.strip_prefix('!') {
+			Some(s) => (true, s),
+			None => (false, s),
+		};
+		let (directory, s) = match s.strip_suffix('/') {
+			Some(s) => (true, s),
+			None => (false, s),
+		};
+		let (anchored, s) = match s.strip_prefix('/') {
+			Some(s) => (true, s),
+			None => (false, s),
+		};
+		if s.is_empty() {
+			return Err(String::from("the pattern is empty"));
+		}
+		Ok(Self {
+			pattern: Pattern::new(s).map_err(|e| e.to_string())?,
+			directory,
+			anchored,
+			negated,
+		})
+	}
+
+	fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+		if self.directory && !is_dir {
+			return false;
+		}
+		self.pattern.matches_path(relative_path)
+			|| (!self.anchored
+				&& relative_path
+					.file_name()
+					.is_some_and(|name| self.pattern.matches_path(Path::new(name))))
+	}
+}
+
+/// Whether a file (or directory) should be skipped, based on its path relative to what's being
+/// watched.
+///
+/// The last pattern that matches decides, so negated patterns only bring back files skipped by
+/// the patterns before them.
+pub fn is_excluded(patterns: &[ExcludePattern], relative_path: &Path, is_dir: bool) -> bool {
+	decide(patterns, relative_path, is_dir).unwrap_or(false)
+}
+
+/// Whether the last of the patterns to match says to skip the file, or `None` if none of them
+/// match it.
+pub fn decide(patterns: &[ExcludePattern], relative_path: &Path, is_dir: bool) -> Option<bool> {
+	patterns
+		.iter()
+		.rev()
+		.find(|pattern| pattern.matches(relative_path, is_dir))
+		.map(|pattern| !pattern.negated)
+}
+
+/// Reads the patterns from an ignore file, one per line, skipping blank lines and lines starting
+/// with `#`.
+///
+/// Invalid patterns are skipped too, and added to the warnings.
+pub fn read_ignore_file(
+	path: &Path,
+	warnings: &mut Vec<String>,
+) -> io::Result<Vec<ExcludePattern>> {
+	let contents = fs::read_to_string(path)?;
+	let mut patterns = Vec::new();
+	for line in contents.lines().map(str::trim_end) {
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		match ExcludePattern::parse(line) {
+			Ok(pattern) => patterns.push(pattern),
+			Err(e) => warnings.push(format!(
+				"Invalid pattern {} in {}: {}",
+				line,
+				path.display(),
+				e
+			)),
+		}
+	}
+	Ok(patterns)
+}
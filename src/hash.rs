@@ -0,0 +1,181 @@
+//! The algorithms that can be used to tell whether a file's content has changed, and how files
+//! are read to hash them.
+
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use siphasher::sip128::{Hasher128, SipHasher};
+use std::{
+	fs::File,
+	hash::Hasher,
+	io::{self, ErrorKind, Read},
+};
+use xxhash_rust::xxh3::Xxh3;
+
+/// The size above which files are memory-mapped to hash them, unless told otherwise.
+const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// The size of the buffer files are read into to hash them, unless told otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How file contents are hashed.
+#[derive(Clone, Copy)]
+pub enum HashAlgorithm {
+	/// Fast, but not something other tools can produce
+	SipHash,
+	Blake3,
+	/// The fastest, and can be checked with `xxhsum -H3`
+	Xxh3,
+	/// Slower, but can be checked with tools like `sha256sum`
+	Sha256,
+}
+
+/// When to memory-map files to hash them, rather than reading them a chunk at a time.
+#[derive(Clone, Copy)]
+pub enum MmapMode {
+	/// Only for files that are big enough for it to pay off
+	Auto,
+	Always,
+	/// For filesystems where mapping is slow or unreliable, like network ones
+	Never,
+}
+
+/// Everything about how watched files are hashed.
+#[derive(Clone, Copy)]
+pub struct HashOptions {
+	pub algorithm: HashAlgorithm,
+	pub mmap: MmapMode,
+	/// How many bytes are read at a time when a file isn't memory-mapped
+	pub buffer_size: usize,
+}
+
+impl Default for HashOptions {
+	fn default() -> Self {
+		Self {
+			algorithm: HashAlgorithm::SipHash,
+			mmap: MmapMode::Auto,
+			buffer_size: DEFAULT_BUFFER_SIZE,
+		}
+	}
+}
+
+impl HashOptions {
+	/// Hashes the contents of a file, returning the digest in hex.
+	///
+	/// Files that can't be memory-mapped (like empty or special files) are read normally instead.
+	pub fn hash_file(&self, file: File) -> io::Result<String> {
+		let len = file.metadata()?.len();
+		let mmap = match self.mmap {
+			MmapMode::Auto => len >= MMAP_THRESHOLD,
+			MmapMode::Always => len > 0,
+			MmapMode::Never => false,
+		};
+		if mmap {
+			// Safety: the mapping is only ever read from, and is dropped before returning. Another
+			// program changing the file while it's mapped can make the hash come out wrong, which
+			// is caught by checking the length afterwards (and by the next check in any case)
+			if let Ok(map) = unsafe { Mmap::map(&file) } {
+				let mut digester = Digester::new(self.algorithm);
+				digester.update(&map);
+				drop(map);
+				if file.metadata()?.len() != len {
+					return Err(io::Error::other(
+						"the file changed size while it was being hashed",
+					));
+				}
+				return Ok(digester.finish());
+			}
+		}
+		self.algorithm.hash_reader_buffered(file, self.buffer_size)
+	}
+}
+
+impl HashAlgorithm {
+	pub fn parse(s: &str) -> Result<Self, String> {
+		match s {
+			"siphash" => Ok(HashAlgorithm::SipHash),
+			"blake3" => Ok(HashAlgorithm::Blake3),
+			"xxh3" => Ok(HashAlgorithm::Xxh3),
+			"sha256" => Ok(HashAlgorithm::Sha256),
+			_ => Err(String::from(
+				"must be one of siphash, blake3, xxh3, or sha256",
+			)),
+		}
+	}
+
+	/// The name the algorithm goes by, as it's given on the command line.
+	pub fn name(&self) -> &'static str {
+		match self {
+			HashAlgorithm::SipHash => "siphash",
+			HashAlgorithm::Blake3 => "blake3",
+			HashAlgorithm::Xxh3 => "xxh3",
+			HashAlgorithm::Sha256 => "sha256",
+		}
+	}
+
+	/// Hashes everything that can be read from the reader, returning the digest in hex.
+	pub fn hash_reader(&self, reader: impl Read) -> io::Result<String> {
+		self.hash_reader_buffered(reader, DEFAULT_BUFFER_SIZE)
+	}
+
+	/// Hashes everything that can be read from the reader, reading `buffer_size` bytes at a time.
+	///
+	/// The digest doesn't depend on the buffer size, only on what was read.
+	pub fn hash_reader_buffered(
+		&self,
+		mut reader: impl Read,
+		buffer_size: usize,
+	) -> io::Result<String> {
+		let mut digester = Digester::new(*self);
+		let mut hash_buffer = vec![0u8; buffer_size.max(1)];
+		loop {
+			// Only the bytes actually read are hashed, since reads can come up short of the buffer
+			// size at any point and not just at the end
+			match reader.read(&mut hash_buffer) {
+				Ok(0) => return Ok(digester.finish()),
+				Ok(n) => digester.update(&hash_buffer[..n]),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+/// A hash in progress.
+enum Digester {
+	SipHash(SipHasher),
+	Blake3(Box<blake3::Hasher>),
+	Xxh3(Box<Xxh3>),
+	Sha256(Sha256),
+}
+
+impl Digester {
+	fn new(algorithm: HashAlgorithm) -> Self {
+		match algorithm {
+			HashAlgorithm::SipHash => Digester::SipHash(SipHasher::new()),
+			HashAlgorithm::Blake3 => Digester::Blake3(Box::new(blake3::Hasher::new())),
+			HashAlgorithm::Xxh3 => Digester::Xxh3(Box::new(Xxh3::new())),
+			HashAlgorithm::Sha256 => Digester::Sha256(Sha256::new()),
+		}
+	}
+
+	fn update(&mut self, bytes: &[u8]) {
+		match self {
+			Digester::SipHash(hasher) => hasher.write(bytes),
+			Digester::Blake3(hasher) => {
+				hasher.update(bytes);
+			}
+			Digester::Xxh3(hasher) => hasher.update(bytes),
+			Digester::Sha256(hasher) => hasher.update(bytes),
+		}
+	}
+
+	/// Finishes the hash, returning the digest in hex.
+	fn finish(self) -> String {
+		match self {
+			Digester::SipHash(hasher) => format!("{:032x}", hasher.finish128().as_u128()),
+			Digester::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+			Digester::Xxh3(hasher) => format!("{:016x}", hasher.digest()),
+			Digester::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+		}
+	}
+}
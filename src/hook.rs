@@ -0,0 +1,90 @@
+//! Running user-supplied commands in response to events.
+
+use std::{
+	io,
+	process::{Command, ExitStatus},
+	thread,
+	time::{Duration, Instant},
+};
+
+/// How often a hook with a timeout is checked on to see whether it's finished.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs the command through the system shell with the given environment variables set, waiting
+/// for it to finish.
+///
+/// If it runs for longer than the timeout, it's killed and `None` is returned.
+pub fn run_hook(
+	command: &str,
+	env: &[(&str, &str)],
+	timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+	let mut shell = if cfg!(windows) {
+		let mut shell = Command::new("cmd");
+		shell.arg("/C");
+		shell
+	} else {
+		let mut shell = Command::new("sh");
+		shell.arg("-c");
+		shell
+	};
+	shell.arg(command).envs(env.iter().copied());
+
+	let timeout = match timeout {
+		Some(timeout) => timeout,
+		None => return shell.status().map(Some),
+	};
+	let mut child = shell.spawn()?;
+	let deadline = Instant::now() + timeout;
+	loop {
+		if let Some(status) = child.try_wait()? {
+			return Ok(Some(status));
+		}
+		if Instant::now() >= deadline {
+			child.kill()?;
+			child.wait()?;
+			return Ok(None);
+		}
+		thread::sleep(TIMEOUT_POLL_INTERVAL);
+	}
+}
+
+/// Fills in the `{placeholder}`s in the command with the given values, quoted so that the shell
+/// always sees each one as a single argument.
+///
+/// Anything in braces that isn't one of the placeholders is left as it is.
+pub fn fill_placeholders(command: &str, values: &[(&str, &str)]) -> String {
+	let mut filled = String::with_capacity(command.len());
+	let mut rest = command;
+	while let Some(start) = rest.find('{') {
+		filled.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let value = rest.find('}').and_then(|end| {
+			values
+				.iter()
+				.find(|(name, _)| *name == &rest[1..end])
+				.map(|(_, value)| (end, value))
+		});
+		match value {
+			Some((end, value)) => {
+				filled.push_str(&quote(value));
+				rest = &rest[end + 1..];
+			}
+			None => {
+				filled.push('{');
+				rest = &rest[1..];
+			}
+		}
+	}
+	filled.push_str(rest);
+	filled
+}
+
+/// Quotes a value for the system shell.
+fn quote(value: &str) -> String {
+	if cfg!(windows) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		format!("'{}'", value.replace('\'', "'\\''"))
+	}
+}
@@ -0,0 +1,70 @@
+//! Watches files and makes backups whenever a change is detected.
+//!
+//! The [`Watcher`] holds everything needed to check the watched files, and can either be driven
+//! one check at a time with [`Watcher::check_once`] or left to [`Watcher::run`] until it's told
+//! to stop.
+
+pub mod backup;
+pub mod codec;
+pub mod config;
+pub mod delta;
+pub mod desktop;
+pub mod diff;
+pub mod duration;
+pub mod encrypt;
+pub mod error;
+mod events;
+pub mod exclude;
+pub mod hash;
+pub mod hook;
+pub mod lock;
+pub mod log;
+pub mod manifest;
+pub mod output;
+pub mod preserve;
+pub mod retention;
+pub mod retry;
+pub mod s3;
+pub mod sftp;
+pub mod size;
+pub mod state;
+pub mod store;
+pub mod systemd;
+mod targets;
+pub mod template;
+pub mod timestamp;
+mod watcher;
+pub mod webhook;
+
+pub use error::WatchError;
+pub use targets::{read_target_list, WatchTarget};
+pub use watcher::{
+	AdaptiveInterval, BackupResult, Control, Controller, Follow, OnDelete, RestoreResult,
+	StopReason, WatchConfig, WatchMode, Watcher,
+};
+
+use chrono::{Local, Utc};
+use hash::{HashAlgorithm, HashOptions};
+use std::{fs, io, path::Path};
+use timestamp::TimestampFormat;
+
+/// Hashes the contents of a file, returning the digest in hex.
+pub fn hash_file(path: &Path, options: &HashOptions) -> io::Result<String> {
+	options.hash_file(fs::File::open(path)?)
+}
+
+/// Hashes the original contents of a backup, so that it can be compared against the file it was
+/// made from.
+pub fn hash_backup(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+	algorithm.hash_reader(codec::open_backup(path)?)
+}
+
+/// Gets the current time as a timestamp suitable for backup names, in either local time or UTC.
+pub fn get_timestamp(format: &TimestampFormat, local_time: bool) -> String {
+	let now = if local_time {
+		Local::now().naive_local()
+	} else {
+		Utc::now().naive_utc()
+	};
+	format.render(now)
+}
@@ -0,0 +1,145 @@
+//! Locks that keep more than one watcher from watching the same file at once, which would end up
+//! with them both backing it up and pruning each other's backups.
+//!
+//! The lock is a file next to the watched file (or in the output directory, when there is one),
+//! which the watcher holds an OS lock on for as long as it's watching. The OS lets go of the lock
+//! if the watcher crashes, so a lock file left behind by a watcher that's gone is just taken over.
+
+use siphasher::sip::SipHasher;
+use std::{
+	fs::{self, File, OpenOptions, TryLockError},
+	hash::Hasher,
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+	process,
+};
+
+/// What's added to the name of a watched file to get the name of its lock file.
+const LOCK_EXTENSION: &str = ".watch-lock";
+
+/// A lock on a watched file, which is let go of when dropped.
+pub struct TargetLock {
+	path: PathBuf,
+	file: File,
+}
+
+impl TargetLock {
+	/// Takes the lock on the watched file, or returns the ID of the process that has it (if it
+	/// can be told).
+	///
+	/// The lock is for the file itself rather than the path to it, so that watching the same file
+	/// through a different path or a symlink is caught too. With a directory, the lock file goes
+	/// in there instead of next to the watched file (which keeps them out of the directories being
+	/// watched when backups go somewhere else).
+	pub fn acquire(watch_file: &Path, dir: Option<&Path>) -> io::Result<Result<Self, Option<u32>>> {
+		let path = lock_path(watch_file, dir)?;
+		if let Some(dir) = dir {
+			fs::create_dir_all(dir)?;
+		}
+		loop {
+			let mut file = OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create(true)
+				.truncate(false)
+				.open(&path)?;
+			match file.try_lock() {
+				Ok(()) => {}
+				Err(TryLockError::WouldBlock) => {
+					let mut contents = String::new();
+					file.read_to_string(&mut contents)?;
+					return Ok(Err(contents.trim().parse().ok()));
+				}
+				Err(TryLockError::Error(e)) => return Err(e),
+			}
+			// The watcher that had the lock may have removed the file in the meantime, in which
+			// case the lock that was just taken is on a file nobody else will look at
+			if !is_same_file(&file, &path) {
+				continue;
+			}
+			let mut lock = Self { path, file };
+			lock.write_pid()?;
+			return Ok(Ok(lock));
+		}
+	}
+
+	/// Writes the ID of the current process to the lock file, so that it's the one named when
+	/// someone else tries to take the lock (like after the watcher has moved to a new process).
+	pub fn write_pid(&mut self) -> io::Result<()> {
+		self.file.set_len(0)?;
+		self.file.seek(SeekFrom::Start(0))?;
+		self.file.write_all(process::id().to_string().as_bytes())?;
+		self.file.sync_data()
+	}
+}
+
+impl Drop for TargetLock {
+	fn drop(&mut self) {
+		// The file is removed while it's still locked, so nobody can take the lock on it just
+		// before it's gone
+		let _ = fs::remove_file(&self.path);
+		let _ = self.file.unlock();
+	}
+}
+
+/// Whether the file is the lock file of a watched file, which shouldn't be watched itself.
+pub fn is_lock_file(path: &Path) -> bool {
+	path.file_name()
+		.and_then(|name| name.to_str())
+		.is_some_and(|name| name.starts_with('.') && name.ends_with(LOCK_EXTENSION))
+}
+
+/// Where the lock file for the watched file goes, which is the same no matter which path the
+/// watched file is reached through.
+///
+/// Lock files in a directory of their own are named after the whole path of the watched file, so
+/// that files with the same name in different directories get different ones.
+fn lock_path(watch_file: &Path, dir: Option<&Path>) -> io::Result<PathBuf> {
+	let real_path = match fs::canonicalize(watch_file) {
+		Ok(real_path) => real_path,
+		// Files that don't exist yet can still be locked, as long as their directory exists
+		Err(_) => {
+			let dir = match watch_file.parent() {
+				Some(dir) if dir != Path::new("") => dir,
+				_ => Path::new("."),
+			};
+			let name = watch_file
+				.file_name()
+				.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+			fs::canonicalize(dir)?.join(name)
+		}
+	};
+	let mut name = std::ffi::OsString::from(".");
+	name.push(real_path.file_name().unwrap_or_default());
+	match dir {
+		Some(dir) => {
+			let mut hasher = SipHasher::new();
+			hasher.write(real_path.to_string_lossy().as_bytes());
+			name.push(format!("-{:016x}{}", hasher.finish(), LOCK_EXTENSION));
+			Ok(dir.join(name))
+		}
+		None => {
+			name.push(LOCK_EXTENSION);
+			Ok(real_path.with_file_name(name))
+		}
+	}
+}
+
+/// Whether the open file is still the one at the path.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+
+	match (file.metadata(), fs::metadata(path)) {
+		(Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+		_ => false,
+	}
+}
+
+/// Whether the open file is still the one at the path.
+///
+/// Open files can't be removed on Windows, so it has to be.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+	true
+}
@@ -0,0 +1,135 @@
+//! A log file that lines are appended to as things happen.
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+/// When to rotate a log file, and how many of the rotated files to keep.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LogRotation {
+	/// How big the log file can get before it's rotated, in bytes
+	pub max_size: u64,
+	/// How many rotated files to keep, named like `watch.log.1` (the newest) to `watch.log.5`
+	pub keep: usize,
+}
+
+/// A file that lines are appended to, which is reopened if it's moved or deleted (like by log
+/// rotation) so that new lines don't keep going to the old file.
+pub struct LogFile {
+	path: PathBuf,
+	file: File,
+	rotation: Option<LogRotation>,
+}
+
+impl LogFile {
+	/// Opens the log file, creating it if it doesn't exist yet.
+	pub fn open(path: &Path) -> io::Result<Self> {
+		Ok(Self {
+			path: path.to_owned(),
+			file: open_append(path)?,
+			rotation: None,
+		})
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Sets when to rotate the log file, if it should be rotated by size at all.
+	pub fn set_rotation(&mut self, rotation: Option<LogRotation>) {
+		self.rotation = rotation;
+	}
+
+	/// Appends the line to the log file, making sure it's written out before returning so that it
+	/// isn't lost if the program crashes.
+	pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+		if self.was_rotated() {
+			self.file = open_append(&self.path)?;
+		}
+		let mut line = line.to_owned();
+		line.push('\n');
+		if let Some(rotation) = self.rotation {
+			let len = self.file.metadata()?.len();
+			// A line that's too big for a log file of its own still gets written to one
+			if len > 0 && len + line.len() as u64 > rotation.max_size {
+				self.rotate(rotation.keep)?;
+			}
+		}
+		self.file.write_all(line.as_bytes())?;
+		self.file.sync_data()
+	}
+
+	/// Moves the log file to `<path>.1`, shifting the rotated files before it along and deleting
+	/// the ones beyond `keep`, then starts a new one.
+	fn rotate(&mut self, keep: usize) -> io::Result<()> {
+		if keep == 0 {
+			fs::remove_file(&self.path)?;
+		} else {
+			remove_if_exists(&self.rotated_path(keep))?;
+			for number in (1..keep).rev() {
+				let rotated = self.rotated_path(number);
+				if rotated.exists() {
+					fs::rename(&rotated, self.rotated_path(number + 1))?;
+				}
+			}
+			fs::rename(&self.path, self.rotated_path(1))?;
+		}
+		self.file = open_append(&self.path)?;
+		Ok(())
+	}
+
+	fn rotated_path(&self, number: usize) -> PathBuf {
+		let mut path = self.path.clone().into_os_string();
+		path.push(format!(".{}", number));
+		PathBuf::from(path)
+	}
+
+	/// Whether the file at the log file's path isn't the one that's open anymore.
+	#[cfg(unix)]
+	fn was_rotated(&self) -> bool {
+		use std::os::unix::fs::MetadataExt;
+
+		match (fs::metadata(&self.path), self.file.metadata()) {
+			(Ok(current), Ok(open)) => current.dev() != open.dev() || current.ino() != open.ino(),
+			_ => true,
+		}
+	}
+
+	/// Whether the file at the log file's path isn't the one that's open anymore.
+	///
+	/// Open files can't be moved or deleted on Windows, so this only needs to check that it still
+	/// exists.
+	#[cfg(not(unix))]
+	fn was_rotated(&self) -> bool {
+		fs::metadata(&self.path).is_err()
+	}
+}
+
+/// The time to put at the start of a line in the log file.
+pub fn timestamp(local_time: bool) -> String {
+	format_time(SystemTime::now(), local_time)
+}
+
+/// Formats the time the same way as the times at the start of lines in the log file.
+pub fn format_time(time: SystemTime, local_time: bool) -> String {
+	if local_time {
+		DateTime::<Local>::from(time).to_rfc3339_opts(SecondsFormat::Millis, false)
+	} else {
+		DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+	}
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+	OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+	match fs::remove_file(path) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+		_ => Ok(()),
+	}
+}
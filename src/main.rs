@@ -1,156 +1,1967 @@
-use chrono::{Datelike, Duration, Timelike, Utc};
-use clap::{App, Arg, ValueHint};
-use siphasher::sip128::{Hasher128, SipHasher};
+use clap::{App, AppSettings, Arg, ArgMatches, ArgSettings, ErrorKind, ValueHint};
+use serde_json::json;
 use std::{
-	fs,
-	hash::Hasher,
-	io::{self, Read},
+	collections::HashMap,
+	env,
+	ffi::OsString,
+	fs, io,
+	path::{Path, PathBuf},
+	process,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread,
+};
+use watch::{
+	codec::Codec,
+	config::{self, ConfigFile, OptionKind, DEFAULT_CONFIG_FILE},
+	duration::{parse_duration, parse_interval},
+	encrypt,
+	exclude::ExcludePattern,
+	hash::{HashAlgorithm, HashOptions, MmapMode},
+	log::LogRotation,
+	output::{ColorChoice, OutputFormat},
+	preserve::Preserve,
+	read_target_list,
+	retention::RetentionPolicy,
+	retry::RetryPolicy,
+	s3::{self, S3Bucket},
+	sftp::SftpDestination,
+	size::{format_size, parse_size},
+	template::{NameTemplate, DEFAULT_TEMPLATE},
+	timestamp::{TimestampFormat, DEFAULT_TIMESTAMP_FORMAT},
+	webhook::{self, Webhook},
+	AdaptiveInterval, Control, Controller, Follow, OnDelete, WatchConfig, WatchError, WatchMode,
+	Watcher,
 };
-use timer::Timer;
-
-struct PollContext {
-	watch_file: String,
-	cached_hash: Option<u128>,
-	quiet: bool,
-}
 
 fn main() {
-	let matches = App::new("Watch")
+	let app = App::new("Watch")
 		.version(env!("CARGO_PKG_VERSION"))
 		.author(env!("CARGO_PKG_AUTHORS"))
-		.about("Watch a file and make backups whenever a change is detected.")
+		.about("Watch files and make backups whenever a change is detected.")
+		.after_help(
+			"Every option can also be set with the environment variable shown with it, which takes \
+			 precedence over the config file but not the command line. Options that are given or \
+			 not can be set to true or false.",
+		)
+		.setting(AppSettings::SubcommandsNegateReqs)
+		// Options from the config file come first, so that the ones on the command line win
+		.global_setting(AppSettings::AllArgsOverrideSelf)
+		.arg(
+			Arg::new("config")
+				.global(true)
+				.long("config")
+				.takes_value(true)
+				.value_name("file")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"A TOML file of options, named the same as the ones here (like interval = \
+					 \"10s\"), with the files to watch as watch = [...] and files with options of \
+					 their own as [[target]] tables - options given here take precedence over the \
+					 ones in it (defaults to watch.toml, if it's in the current directory) \
+					 [env: WATCH_CONFIG]",
+				),
+		)
 		.arg(
 			Arg::new("watch-file")
-				.required(true)
+				.required_unless_present_any(["config", "from-file"])
 				.index(1)
+				.multiple(true)
+				.value_hint(ValueHint::FilePath)
+				.about("The file(s) to watch - quoted glob patterns are re-evaluated on every poll")
+				.long_about(
+					"The file(s) to watch.\n\nGlob patterns like `configs/*.toml` are expanded by \
+					 the program itself and re-evaluated on every poll, so newly created files \
+					 that match start being watched automatically. Note that most shells expand \
+					 globs before the program ever sees them unless they're quoted, in which case \
+					 only the files that existed at startup are watched.",
+				),
+		)
+		.arg(
+			Arg::new("from-file")
+				.long("from-file")
+				.takes_value(true)
+				.multiple_occurrences(true)
+				.value_name("file")
 				.value_hint(ValueHint::FilePath)
-				.about("The file to watch"),
+				.about(
+					"A file listing files to watch (or glob patterns), one per line, which are \
+					 watched along with any given here - blank lines and lines starting with # are \
+					 skipped, and it's read again when reloading [env: WATCH_FROM_FILE]",
+				),
 		)
 		.arg(
 			Arg::new("interval")
 				.short('i')
 				.long("interval")
 				.takes_value(true)
-				.default_value("5000")
-				.validator(|s| match s.parse::<u64>() {
-					Ok(v) => {
-						if v == 0 {
-							Err(String::from("must be greater than 0"))
-						} else {
-							Ok(())
-						}
+				.default_value("5s")
+				.validator(parse_interval)
+				.about(
+					"Sets the polling interval for file change checks, like 500ms, 5s, 2m, or \
+					 1m30s (a bare number is taken as milliseconds) [env: WATCH_INTERVAL]",
+				),
+		)
+		.arg(
+			Arg::new("adaptive")
+				.long("adaptive")
+				.about(
+					"When polling, check more often right after a change and less and less often \
+					 while nothing changes, within --min-interval and --max-interval \
+					 [env: WATCH_ADAPTIVE]",
+				),
+		)
+		.arg(
+			Arg::new("min-interval")
+				.long("min-interval")
+				.takes_value(true)
+				.validator(parse_interval)
+				.requires("adaptive")
+				.about(
+					"The shortest the polling interval can get with --adaptive (500ms by default) \
+					 [env: WATCH_MIN_INTERVAL]",
+				),
+		)
+		.arg(
+			Arg::new("max-interval")
+				.long("max-interval")
+				.takes_value(true)
+				.validator(parse_interval)
+				.requires("adaptive")
+				.about(
+					"The longest the polling interval can get with --adaptive (1m by default) \
+					 [env: WATCH_MAX_INTERVAL]",
+				),
+		)
+		.arg(
+			Arg::new("watch-mode")
+				.short('m')
+				.long("watch-mode")
+				.takes_value(true)
+				.possible_values(&["poll", "events"])
+				.default_value("events")
+				.about(
+					"Whether to poll for changes on an interval, or to check for changes when the \
+					 OS reports filesystem activity [env: WATCH_WATCH_MODE]",
+				),
+		)
+		.arg(
+			Arg::new("debounce")
+				.short('d')
+				.long("debounce")
+				.takes_value(true)
+				.default_value("200")
+				.validator(parse_interval)
+				.about(
+					"How long filesystem activity has to settle down for before checking for \
+					 changes, like 500ms or 2s (a bare number is in milliseconds). To also wait \
+					 for the file's content to stay the same, use --settle [env: WATCH_DEBOUNCE]",
+				),
+		)
+		.arg(
+			Arg::new("recursive")
+				.short('r')
+				.long("recursive")
+				.about(
+					"Whether to watch every file inside of directories being watched \
+					 [env: WATCH_RECURSIVE]",
+				),
+		)
+		.arg(
+			Arg::new("exclude")
+				.short('x')
+				.long("exclude")
+				.visible_alias("ignore")
+				.takes_value(true)
+				.multiple_occurrences(true)
+				.validator(|s| ExcludePattern::parse(s).map(|_| ()))
+				.about(
+					"A glob pattern for files to skip, matched against both the file name and its \
+					 path relative to what's being watched (can be given multiple times) - like in \
+					 a .gitignore, dir/ only matches directories, /path only matches from the top, \
+					 and !pattern brings back files skipped before it [env: WATCH_EXCLUDE]",
+				),
+		)
+		.arg(
+			Arg::new("use-gitignore")
+				.long("use-gitignore")
+				.about(
+					"Also skip the files skipped by .gitignore files in the directories being \
+					 watched [env: WATCH_USE_GITIGNORE]",
+				),
+		)
+		.arg(
+			Arg::new("output-dir")
+				.global(true)
+				.short('o')
+				.long("output-dir")
+				.takes_value(true)
+				.value_hint(ValueHint::DirPath)
+				.about(
+					"The directory to place backups in, instead of next to the watched file \
+					 [env: WATCH_OUTPUT_DIR]",
+				),
+		)
+		.arg(
+			Arg::new("max-backups")
+				.global(true)
+				.short('n')
+				.long("max-backups")
+				.takes_value(true)
+				.validator(|s| {
+					s.parse::<usize>()
+						.map(|_| ())
+						.map_err(|_| String::from("must be parsable as usize"))
+				})
+				.about(
+					"The maximum number of backups to keep for each file, deleting the oldest ones \
+					 beyond that (0 keeps everything) [env: WATCH_MAX_BACKUPS]",
+				),
+		)
+		.arg(
+			Arg::new("keep-for")
+				.global(true)
+				.long("keep-for")
+				.visible_alias("max-age")
+				.takes_value(true)
+				.validator(parse_duration)
+				.about(
+					"How long to keep backups for before deleting them, like 90m, 48h, or 7d \
+					 (based on the time in the backup name) [env: WATCH_KEEP_FOR]",
+				),
+		)
+		.arg(
+			Arg::new("max-total-size")
+				.global(true)
+				.long("max-total-size")
+				.takes_value(true)
+				.validator(parse_size)
+				.about(
+					"The maximum combined size of the backups kept for each file, like 500M or \
+					 20G, deleting the oldest ones beyond that [env: WATCH_MAX_TOTAL_SIZE]",
+				),
+		)
+		.arg(
+			Arg::new("retention")
+				.global(true)
+				.long("retention")
+				.takes_value(true)
+				.value_name("policy")
+				.validator(RetentionPolicy::parse)
+				.about(
+					"Keep fewer backups as they get older, like 24h:all,7d:daily,30d:weekly \
+					 (backups older than the last tier are deleted) [env: WATCH_RETENTION]",
+				)
+				.long_about(
+					"Keep fewer backups as they get older, based on the time in the backup \
+					 names.\n\nThe policy is a list of tiers like 24h:all,7d:daily,30d:weekly, \
+					 which keeps every backup from the last 24 hours, the newest backup of each \
+					 day for the last 7 days, and the newest backup of each week for the last 30 \
+					 days. The backups kept in each tier can be all, hourly, daily, weekly, \
+					 monthly, or a duration like 6h. Backups older than the last tier are deleted, \
+					 except for the newest backup, which is always kept. [env: WATCH_RETENTION]",
+				),
+		)
+		.arg(
+			Arg::new("compress")
+				.global(true)
+				.short('z')
+				.long("compress")
+				.takes_value(true)
+				.min_values(0)
+				.require_equals(true)
+				.default_missing_value("gzip")
+				.validator(|s| Codec::parse(s).map(|_| ()))
+				.about(
+					"Compress backups, optionally choosing the codec and level like \
+					 --compress=zstd:3 (gzip or zstd, defaulting to gzip) [env: WATCH_COMPRESS]",
+				),
+		)
+		.arg(
+			Arg::new("delta")
+				.global(true)
+				.long("delta")
+				.conflicts_with("compress")
+				.about(
+					"Store backups as binary deltas against the backup before them, which saves a \
+					 lot of space for big files with small changes [env: WATCH_DELTA]",
+				),
+		)
+		.arg(
+			Arg::new("encrypt")
+				.global(true)
+				.long("encrypt")
+				.conflicts_with("delta")
+				.about(
+					"Encrypt backups with a passphrase (from --passphrase-file or the \
+					 WATCH_PASSPHRASE environment variable), in the age format \
+					 [env: WATCH_ENCRYPT]",
+				),
+		)
+		.arg(
+			Arg::new("passphrase-file")
+				.global(true)
+				.long("passphrase-file")
+				.takes_value(true)
+				.value_name("file")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"A file holding the passphrase to encrypt backups with, and to decrypt them \
+					 with when restoring [env: WATCH_PASSPHRASE_FILE]",
+				),
+		)
+		.arg(
+			Arg::new("full-every")
+				.global(true)
+				.long("full-every")
+				.takes_value(true)
+				.value_name("backups")
+				.default_value("10")
+				.validator(|s| match s.parse::<usize>() {
+					Ok(v) if v > 0 => Ok(()),
+					_ => Err(String::from("must be a whole number greater than 0")),
+				})
+				.about(
+					"With --delta, how often to make a full backup instead of a delta, so that \
+					 chains of deltas don't get too long to rebuild [env: WATCH_FULL_EVERY]",
+				),
+		)
+		.arg(
+			Arg::new("dedup")
+				.long("dedup")
+				.about(
+					"Don't back up content that's the same as one of the file's existing backups, \
+					 like when a change is undone [env: WATCH_DEDUP]",
+				),
+		)
+		.arg(
+			Arg::new("preserve")
+				.global(true)
+				.long("preserve")
+				.takes_value(true)
+				.possible_values(&["none", "mode", "times", "all"])
+				.default_value("all")
+				.about(
+					"Which metadata of the watched file its backups keep - the permissions (mode), \
+					 the access and modification times (times), both, or neither \
+					 [env: WATCH_PRESERVE]",
+				),
+		)
+		.arg(
+			Arg::new("no-preserve")
+				.global(true)
+				.long("no-preserve")
+				.about(
+					"Don't carry any metadata of the watched files over to their backups (the same \
+					 as --preserve none, for filesystems that don't support setting it) \
+					 [env: WATCH_NO_PRESERVE]",
+				),
+		)
+		.arg(
+			Arg::new("hash")
+				.global(true)
+				.long("hash")
+				.takes_value(true)
+				.possible_values(&["siphash", "blake3", "xxh3", "sha256"])
+				.default_value("siphash")
+				.about(
+					"The algorithm to hash files with to tell whether they've changed (xxh3 and \
+					 sha256 can be checked against tools like xxhsum and sha256sum) \
+					 [env: WATCH_HASH_ALGORITHM]",
+				),
+		)
+		.arg(
+			Arg::new("mmap")
+				.global(true)
+				.long("mmap")
+				.conflicts_with("no-mmap")
+				.about(
+					"Memory-map files to hash them no matter their size, instead of only ones over \
+					 64 MiB [env: WATCH_MMAP]",
+				),
+		)
+		.arg(
+			Arg::new("no-mmap")
+				.global(true)
+				.long("no-mmap")
+				.about(
+					"Never memory-map files to hash them, reading them in chunks instead (for \
+					 network filesystems) [env: WATCH_NO_MMAP]",
+				),
+		)
+		.arg(
+			Arg::new("hash-buffer")
+				.global(true)
+				.long("hash-buffer")
+				.takes_value(true)
+				.default_value("1M")
+				.validator(|s| match parse_size(s) {
+					Ok(0) => Err(String::from("the buffer size must be greater than 0")),
+					Ok(v) if v > usize::MAX as u64 => {
+						Err(String::from("the buffer size is too large"))
 					}
-					Err(_) => Err(String::from("must be parsable as u64")),
+					Ok(_) => Ok(()),
+					Err(e) => Err(e),
+				})
+				.about(
+					"How much of a file is read at a time to hash it, when it isn't memory-mapped \
+					 (like 64K or 4M) [env: WATCH_HASH_BUFFER]",
+				),
+		)
+		.arg(
+			Arg::new("hardlink-dedup")
+				.global(true)
+				.long("hardlink-dedup")
+				.about(
+					"Make backups with the same content as an earlier one hardlinks to it instead \
+					 of copies, through a store kept in the backup directory (or the output \
+					 directory) [env: WATCH_HARDLINK_DEDUP]",
+				),
+		)
+		.arg(
+			Arg::new("no-fast-path")
+				.long("no-fast-path")
+				.visible_alias("always-hash")
+				.about(
+					"Hash files on every check, even when their size and modification time haven't \
+					 changed (for filesystems with unreliable modification times) \
+					 [env: WATCH_NO_FAST_PATH]",
+				),
+		)
+		.arg(
+			Arg::new("verify")
+				.global(true)
+				.long("verify")
+				.about(
+					"Check that each backup has the same content as the file by reading it back \
+					 after it's made, making it again once if not [env: WATCH_VERIFY]",
+				),
+		)
+		.arg(
+			Arg::new("lock-retries")
+				.global(true)
+				.long("lock-retries")
+				.takes_value(true)
+				.default_value("3")
+				.validator(|s| {
+					s.parse::<u32>()
+						.map(|_| ())
+						.map_err(|_| String::from("must be parsable as u32"))
+				})
+				.about(
+					"How many times to retry reading a file that's locked by another program \
+					 before giving up until the next check [env: WATCH_LOCK_RETRIES]",
+				),
+		)
+		.arg(
+			Arg::new("lock-retry-delay")
+				.global(true)
+				.long("lock-retry-delay")
+				.takes_value(true)
+				.default_value("100ms")
+				.validator(parse_interval)
+				.about(
+					"How long to wait before the first retry, doubling with each one after that \
+					 [env: WATCH_LOCK_RETRY_DELAY]",
+				),
+		)
+		.arg(
+			Arg::new("dry-run")
+				.long("dry-run")
+				.about(
+					"Print the backups that would be made without making them, and don't delete \
+					 any old ones [env: WATCH_DRY_RUN]",
+				),
+		)
+		.arg(
+			Arg::new("copy-retries")
+				.global(true)
+				.long("copy-retries")
+				.takes_value(true)
+				.default_value("3")
+				.validator(|s| {
+					s.parse::<u32>()
+						.map(|_| ())
+						.map_err(|_| String::from("must be parsable as u32"))
+				})
+				.about(
+					"How many times to retry making a backup of a file that's locked by another \
+					 program while it's being copied, before giving up until the next change \
+					 [env: WATCH_COPY_RETRIES]",
+				),
+		)
+		.arg(
+			Arg::new("copy-retry-delay")
+				.global(true)
+				.long("copy-retry-delay")
+				.visible_alias("retry-delay")
+				.takes_value(true)
+				.default_value("100ms")
+				.validator(parse_interval)
+				.about(
+					"How long to wait before the first copy retry (doubling with each one after) \
+					 [env: WATCH_COPY_RETRY_DELAY]",
+				),
+		)
+		.arg(
+			Arg::new("name-template")
+				.global(true)
+				.short('t')
+				.long("name-template")
+				.takes_value(true)
+				.default_value(DEFAULT_TEMPLATE)
+				.validator(|s| NameTemplate::parse(s).map(|_| ()))
+				.about(
+					"The template to name backups with, using the placeholders {name}, {stem}, \
+					 {ext}, {timestamp}, and {hash} (may include directories) \
+					 [env: WATCH_NAME_TEMPLATE]",
+				),
+		)
+		.arg(
+			Arg::new("timestamp-format")
+				.global(true)
+				.long("timestamp-format")
+				.takes_value(true)
+				.default_value(DEFAULT_TIMESTAMP_FORMAT)
+				.validator(|s| TimestampFormat::parse(s).map(|_| ()))
+				.about(
+					"The strftime format for the {timestamp} placeholder, like %Y-%m-%d_%H-%M-%S \
+					 (must not produce path separators) [env: WATCH_TIMESTAMP_FORMAT]",
+				),
+		)
+		.arg(
+			Arg::new("numbering")
+				.global(true)
+				.long("numbering")
+				.about(
+					"Fill the {timestamp} placeholder with sequential numbers like 0001 instead, \
+					 continuing on from the highest existing backup [env: WATCH_NUMBERING]",
+				),
+		)
+		.arg(
+			Arg::new("diff")
+				.long("diff")
+				.about(
+					"Print a unified diff of the changes to text files each time they're backed up \
+					 [env: WATCH_DIFF]",
+				),
+		)
+		.arg(
+			Arg::new("manifest")
+				.global(true)
+				.long("manifest")
+				.takes_value(true)
+				.value_name("path")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"A file to keep a record of every backup in (one JSON object per line), so \
+					 that the list and restore subcommands don't need to hash backups \
+					 [env: WATCH_MANIFEST]",
+				),
+		)
+		.arg(
+			Arg::new("on-change")
+				.long("on-change")
+				.takes_value(true)
+				.about(
+					"A shell command to run after each backup, with {file}, {backup}, {timestamp}, \
+					 and {hash} filled in (and set as the environment variables WATCH_FILE, \
+					 WATCH_BACKUP, WATCH_TIMESTAMP, and WATCH_HASH) [env: WATCH_ON_CHANGE]",
+				),
+		)
+		.arg(
+			Arg::new("on-error")
+				.long("on-error")
+				.takes_value(true)
+				.about(
+					"A shell command to run when an error comes up while watching, with {kind}, \
+					 {message}, and {file} filled in (and set as the environment variables \
+					 WATCH_ERROR_KIND, WATCH_ERROR, and WATCH_FILE) [env: WATCH_ON_ERROR]",
+				),
+		)
+		.arg(
+			Arg::new("on-error-interval")
+				.long("on-error-interval")
+				.takes_value(true)
+				.default_value("10m")
+				.validator(parse_interval)
+				.about(
+					"How long to wait before running the --on-error command or showing a desktop \
+					 notification again for the same error, so that a lasting problem doesn't set \
+					 them off on every check [env: WATCH_ON_ERROR_INTERVAL]",
+				),
+		)
+		.arg(
+			Arg::new("notify-desktop")
+				.long("notify-desktop")
+				.visible_alias("notify")
+				.about(
+					"Show a desktop notification for each backup and error \
+					 [env: WATCH_NOTIFY_DESKTOP]",
+				),
+		)
+		.arg(
+			Arg::new("webhook")
+				.long("webhook")
+				.takes_value(true)
+				.value_name("url")
+				.value_hint(ValueHint::Url)
+				.about("A URL to POST the details of each backup to, as JSON [env: WATCH_WEBHOOK]")
+				.long_about(
+					"A URL to POST the details of each backup to, as a JSON object \
+					 like:\n\n{\"file\": \"notes.txt\", \"backup\": \
+					 \"notes.txt.20211015120000000.bak\",\n \"timestamp\": \"20211015120000000\", \
+					 \"hash\": \"<hex>\", \"size\": 1234}\n\nThe size is of the file, in bytes. \
+					 Requests that can't be delivered are retried a couple of times, then reported \
+					 without stopping the watcher. [env: WATCH_WEBHOOK]",
+				),
+		)
+		.arg(
+			Arg::new("webhook-header")
+				.long("webhook-header")
+				.takes_value(true)
+				.multiple_occurrences(true)
+				.requires("webhook")
+				.value_name("header")
+				.validator(webhook::parse_header)
+				.about(
+					"A header to send with --webhook requests, like 'Authorization: Bearer \
+					 <token>' (can be given multiple times) [env: WATCH_WEBHOOK_HEADER]",
+				),
+		)
+		.arg(
+			Arg::new("webhook-timeout")
+				.long("webhook-timeout")
+				.takes_value(true)
+				.requires("webhook")
+				.validator(parse_interval)
+				.about(
+					"How long each --webhook request can take before it's given up on, like 30s \
+					 (10s by default) [env: WATCH_WEBHOOK_TIMEOUT]",
+				),
+		)
+		.arg(
+			Arg::new("s3")
+				.long("s3")
+				.takes_value(true)
+				.value_name("bucket/prefix")
+				.validator(s3::parse_location)
+				.about(
+					"An S3-compatible bucket (and prefix) to upload each backup to [env: WATCH_S3]",
+				)
+				.long_about(
+					"An S3-compatible bucket to upload each backup to, under the same name it has \
+					 locally, with an optional prefix like my-bucket/backups.\n\n\
+					 The credentials come from the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and \
+					 AWS_SESSION_TOKEN environment variables, the region from AWS_REGION, and the \
+					 endpoint to use instead of AWS (like a MinIO server) from AWS_ENDPOINT_URL. \
+					 Uploads that fail are retried a couple of times, then reported without \
+					 stopping the watcher. [env: WATCH_S3]",
+				),
+		)
+		.arg(
+			Arg::new("s3-delete-local")
+				.long("s3-delete-local")
+				.requires("s3")
+				.conflicts_with_all(&["delta", "hardlink-dedup"])
+				.about(
+					"Remove each backup once it's been uploaded with --s3, keeping the ones that \
+					 couldn't be [env: WATCH_S3_DELETE_LOCAL]",
+				),
+		)
+		.arg(
+			Arg::new("sftp")
+				.long("sftp")
+				.takes_value(true)
+				.value_name("[user@]host:path")
+				.validator(|s| SftpDestination::parse(s).map(|_| ()))
+				.about(
+					"A directory on a remote host to send each backup to over SFTP \
+					 [env: WATCH_SFTP]",
+				)
+				.long_about(
+					"A directory on a remote host to send each backup to over SFTP, under the same \
+					 name it has locally, like user@host:backups (or sftp://user@host:2222/backups \
+					 for another port). Directories that aren't there are made.\n\n\
+					 The host has to be in the known hosts file already, and logging in is done \
+					 through the SSH agent unless --sftp-key is given. Backups that can't be sent \
+					 are retried a couple of times (connecting again each time), then reported \
+					 without stopping the watcher. [env: WATCH_SFTP]",
+				),
+		)
+		.arg(
+			Arg::new("sftp-key")
+				.long("sftp-key")
+				.takes_value(true)
+				.requires("sftp")
+				.value_name("file")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"The private key to log in to the --sftp host with, instead of the SSH agent \
+					 (it can't have a passphrase) [env: WATCH_SFTP_KEY]",
+				),
+		)
+		.arg(
+			Arg::new("sftp-known-hosts")
+				.long("sftp-known-hosts")
+				.takes_value(true)
+				.requires("sftp")
+				.value_name("file")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"The known hosts file to check the --sftp host against, instead of \
+					 ~/.ssh/known_hosts [env: WATCH_SFTP_KNOWN_HOSTS]",
+				),
+		)
+		.arg(
+			Arg::new("hook-timeout")
+				.long("hook-timeout")
+				.takes_value(true)
+				.validator(parse_interval)
+				.about(
+					"How long the --on-change and --on-error commands can run before they're \
+					 killed, like 30s or 5m (otherwise the watch waits for them to finish) \
+					 [env: WATCH_HOOK_TIMEOUT]",
+				),
+		)
+		.arg(
+			Arg::new("local")
+				.global(true)
+				.long("local")
+				.visible_alias("local-time")
+				.conflicts_with("utc")
+				.about("Use local time for the timestamps in backup names [env: WATCH_LOCAL]"),
+		)
+		.arg(
+			Arg::new("utc")
+				.global(true)
+				.long("utc")
+				.about("Use UTC for the timestamps in backup names (the default) [env: WATCH_UTC]"),
+		)
+		.arg(
+			Arg::new("state-file")
+				.global(true)
+				.long("state-file")
+				.takes_value(true)
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"A file to remember the last known hashes in between runs, so that changes \
+					 made while the program wasn't running get backed up on startup \
+					 [env: WATCH_STATE_FILE]",
+				),
+		)
+		.arg(
+			Arg::new("cooldown")
+				.long("cooldown")
+				.visible_alias("min-backup-interval")
+				.takes_value(true)
+				.validator(parse_interval)
+				.about(
+					"The minimum time between backups of a file, like 10m - changes made sooner \
+					 are held off on until the cooldown is over, and only the latest content is \
+					 backed up [env: WATCH_COOLDOWN]",
+				),
+		)
+		.arg(
+			Arg::new("settle")
+				.long("settle")
+				.takes_value(true)
+				.validator(parse_interval)
+				.about(
+					"Once a change is noticed, wait until the file has stayed the same for this \
+					 long before backing it up, like 2s (for files that take a while to write) \
+					 [env: WATCH_SETTLE]",
+				),
+		)
+		.arg(
+			Arg::new("run-for")
+				.long("run-for")
+				.takes_value(true)
+				.validator(parse_interval)
+				.about(
+					"Stop watching by itself after this long, like 2h or 90m [env: WATCH_RUN_FOR]",
+				),
+		)
+		.arg(
+			Arg::new("max-changes")
+				.long("max-changes")
+				.takes_value(true)
+				.validator(|s| match s.parse::<usize>() {
+					Ok(0) => Err(String::from("must be greater than 0")),
+					Ok(_) => Ok(()),
+					Err(_) => Err(String::from("must be parsable as usize")),
 				})
-				.about("Sets the polling interval for file change checks, in milliseconds"),
+				.about(
+					"Stop watching by itself after this many changes have been backed up (not \
+					 counting starting backups, unless given --count-starting-backups) \
+					 [env: WATCH_MAX_CHANGES]",
+				),
+		)
+		.arg(
+			Arg::new("count-starting-backups")
+				.long("count-starting-backups")
+				.requires("max-changes")
+				.about(
+					"Count starting backups as changes towards --max-changes \
+					 [env: WATCH_COUNT_STARTING_BACKUPS]",
+				),
+		)
+		.arg(
+			Arg::new("fail-if-missing")
+				.long("fail-if-missing")
+				.about(
+					"Exit with an error if a watched file doesn't exist at startup, instead of \
+					 waiting for it to be created [env: WATCH_FAIL_IF_MISSING]",
+				),
+		)
+		.arg(
+			Arg::new("no-follow-symlinks")
+				.long("no-follow-symlinks")
+				.about(
+					"Watch symlinks themselves rather than what they point to, so that pointing \
+					 one somewhere else counts as a change (what it points to is still what's \
+					 backed up) [env: WATCH_NO_FOLLOW_SYMLINKS]",
+				),
+		)
+		.arg(
+			Arg::new("follow-rename")
+				.long("follow-rename")
+				.about(
+					"Look out for watched files being renamed within their directory, and say so \
+					 when they are [env: WATCH_FOLLOW_RENAME]",
+				),
+		)
+		.arg(
+			Arg::new("follow")
+				.long("follow")
+				.takes_value(true)
+				.requires("follow-rename")
+				.possible_values(&["path", "inode"])
+				.about(
+					"What to keep watching when a watched file is renamed with --follow-rename - \
+					 the file at its new path (inode, which is the default), or whatever is at the \
+					 original path (path). Backups are named after the original path either way \
+					 [env: WATCH_FOLLOW]",
+				),
+		)
+		.arg(
+			Arg::new("on-delete")
+				.long("on-delete")
+				.takes_value(true)
+				.possible_values(&["wait", "exit", "backup-last"])
+				.default_value("wait")
+				.about(
+					"What to do when a watched file is deleted - wait for it to come back, exit \
+					 with an error, or wait while keeping its newest backup from being pruned \
+					 [env: WATCH_ON_DELETE]",
+				),
+		)
+		.arg(
+			Arg::new("exit-on-delete")
+				.long("exit-on-delete")
+				.about(
+					"Exit with an error when a watched file is deleted (like --on-delete exit) \
+					 [env: WATCH_EXIT_ON_DELETE]",
+				),
+		)
+		.arg(
+			Arg::new("backup-on-delete")
+				.long("backup-on-delete")
+				.about(
+					"When a watched file is deleted, say which backup has the last content it had \
+					 [env: WATCH_BACKUP_ON_DELETE]",
+				),
+		)
+		.arg(
+			Arg::new("missing-grace")
+				.long("missing-grace")
+				.takes_value(true)
+				.validator(|s| {
+					s.parse::<usize>()
+						.map(|_| ())
+						.map_err(|_| String::from("must be parsable as usize"))
+				})
+				.about(
+					"How many checks in a row a watched file can be missing (or unreadable) for \
+					 before giving up and exiting with an error [env: WATCH_MISSING_GRACE]",
+				),
+		)
+		.arg(
+			Arg::new("threads")
+				.long("threads")
+				.takes_value(true)
+				.validator(|s| match s.parse::<usize>() {
+					Ok(n) if n > 0 => Ok(()),
+					_ => Err(String::from("must be a positive number")),
+				})
+				.about(
+					"How many watched files to hash at once when checking for changes, defaulting \
+					 to one per core [env: WATCH_THREADS]",
+				),
+		)
+		.arg(
+			Arg::new("strict")
+				.long("strict")
+				.about(
+					"Exit on the first error (like a failed hash or copy) instead of reporting it \
+					 and carrying on [env: WATCH_STRICT]",
+				),
 		)
 		.arg(
 			Arg::new("quiet")
+				.global(true)
 				.short('q')
 				.long("quiet")
-				.about("Whether to be silent under normal operation"),
+				.about("Whether to be silent under normal operation [env: WATCH_QUIET]"),
+		)
+		.arg(
+			Arg::new("verbose")
+				.global(true)
+				.short('v')
+				.long("verbose")
+				.multiple_occurrences(true)
+				.conflicts_with("quiet")
+				.about(
+					"Report more of what's going on - once for every check of a file (even without \
+					 a change), or twice for the size and modification time of each file that's \
+					 hashed and how long hashing and backing up take too [env: WATCH_VERBOSE]",
+				),
+		)
+		.arg(
+			Arg::new("log-file")
+				.long("log-file")
+				.takes_value(true)
+				.value_name("path")
+				.value_hint(ValueHint::FilePath)
+				.about(
+					"A file to add timestamped messages to (or the JSON events, with --format \
+					 json), which gets everything even with --quiet and is reopened if it's \
+					 rotated [env: WATCH_LOG_FILE]",
+				),
+		)
+		.arg(
+			Arg::new("log-max-size")
+				.long("log-max-size")
+				.takes_value(true)
+				.value_name("size")
+				.validator(parse_size)
+				.requires("log-file")
+				.about(
+					"How big the log file can get before it's rotated, like 10MiB - the rotated \
+					 files are named like watch.log.1, with the newest being .1 \
+					 [env: WATCH_LOG_MAX_SIZE]",
+				),
+		)
+		.arg(
+			Arg::new("log-keep")
+				.long("log-keep")
+				.takes_value(true)
+				.value_name("files")
+				.validator(|s| {
+					s.parse::<usize>()
+						.map(|_| ())
+						.map_err(|_| String::from("must be parsable as usize"))
+				})
+				.requires("log-max-size")
+				.about("How many rotated log files to keep (5 by default) [env: WATCH_LOG_KEEP]"),
+		)
+		.arg(
+			Arg::new("color")
+				.global(true)
+				.long("color")
+				.takes_value(true)
+				.possible_values(&["auto", "always", "never"])
+				.default_value("auto")
+				.about(
+					"Whether to colour messages - auto only does when printing to a terminal and \
+					 NO_COLOR isn't set [env: WATCH_COLOR]",
+				),
+		)
+		.arg(
+			Arg::new("format")
+				.long("format")
+				.takes_value(true)
+				.possible_values(&["text", "json"])
+				.default_value("text")
+				.about(
+					"Whether to print messages for people, or one JSON object per line for each \
+					 event [env: WATCH_FORMAT]",
+				)
+				.long_about(
+					"Whether to print messages for people, or one JSON object per line for each \
+					 event, with the event's name in the event field:\n\n\
+					 started: mode (events or poll)\n\
+					 backup_created: timestamp, file, backup, hash, size (of the file, in bytes), \
+					 starting (whether it's the first backup since starting), manual (whether it \
+					 was asked for with SIGUSR1 or b)\n\
+					 duplicate: timestamp, file, backup (the existing one), hash - for changes \
+					 whose content was already backed up\n\
+					 pruned: file, backup\n\
+					 no_change: file, hash - only with --verbose\n\
+					 error: kind, message, file (which may be null)\n\
+					 status: file, hash, last_backup_ms, backups, paused - when status is entered, \
+					 even with --quiet\n\
+					 summary: ran_for_ms, checks, backups, bytes_written, newest_backup (which may \
+					 be null)\n\
+					 shutdown: reason (shutdown, run_for, or max_changes), changes\n\n\
+					 In JSON mode nothing else is printed on stdout, and --quiet leaves only the \
+					 errors. [env: WATCH_FORMAT]",
+				),
 		)
 		.arg(
 			Arg::new("starting-backup")
 				.short('s')
 				.long("starting-backup")
-				.about("Whether or not to make a backup of the file upon startup of the program"),
+				.about(
+					"Whether or not to make a backup of the file upon startup of the program \
+					 [env: WATCH_STARTING_BACKUP]",
+				),
+		)
+		.arg(
+			Arg::new("final-backup")
+				.long("final-backup")
+				.about(
+					"Check for changes one last time when shutting down, so that the latest \
+					 content is backed up [env: WATCH_FINAL_BACKUP]",
+				),
+		)
+		.arg(
+			Arg::new("allow-multiple")
+				.long("allow-multiple")
+				.about(
+					"Watch files even when another watcher is already watching them, rather than \
+					 refusing to [env: WATCH_ALLOW_MULTIPLE]",
+				),
+		)
+		.arg(
+			Arg::new("daemon")
+				.long("daemon")
+				.about(
+					"Ignore stdin, running until a termination signal is received (for running \
+					 as a service) [env: WATCH_DAEMON]",
+				),
+		)
+		.arg(
+			Arg::new("detach")
+				.long("detach")
+				.requires("log-file")
+				.about(
+					"Run in the background, detached from the terminal, once the starting backups \
+					 are made (only on Unix) - implies --daemon, and since nothing is printed \
+					 after detaching, it needs --log-file [env: WATCH_DETACH]",
+				),
 		)
-		.get_matches();
+		.arg(
+			Arg::new("once")
+				.long("once")
+				.conflicts_with("starting-backup")
+				.about(
+					"Check for changes a single time and exit, comparing against the state file or \
+					 the newest existing backup (exits with 0 for no changes, 1 if a backup was \
+					 made, or 2 on errors) [env: WATCH_ONCE]",
+				),
+		)
+		.subcommand(
+			App::new("restore")
+				.about("Restore a file from one of its backups")
+				.long_about(
+					"Restore a file from one of its backups - the newest one, or the one made at \
+					 the timestamp given with --at.\n\nUnless the current content of the file is \
+					 already its newest backup, it's backed up first so that nothing is lost. The \
+					 same options used to make the backups (like --output-dir and --name-template) \
+					 need to be given for them to be found.",
+				)
+				.arg(
+					Arg::new("file")
+						.required(true)
+						.index(1)
+						.value_hint(ValueHint::FilePath)
+						.about("The file to restore"),
+				)
+				.arg(
+					Arg::new("at")
+						.long("at")
+						.takes_value(true)
+						.value_name("timestamp")
+						.about(
+							"The timestamp of the backup to restore, as it appears in the backup's \
+							 name (defaults to the newest backup)",
+						),
+				)
+				.arg(
+					Arg::new("dry-run")
+						.long("dry-run")
+						.about("Print what would be done without changing anything"),
+				),
+		)
+		.subcommand(
+			App::new("list")
+				.about("List the backups of a file, newest first")
+				.long_about(
+					"List the backups of a file with their timestamps, sizes, and hashes (when \
+					 they're part of the backup names), newest first.\n\nThe same options used to \
+					 make the backups (like --output-dir and --name-template) need to be given for \
+					 them to be found.",
+				)
+				.arg(
+					Arg::new("file")
+						.required(true)
+						.index(1)
+						.value_hint(ValueHint::FilePath)
+						.about("The file to list the backups of"),
+				)
+				.arg(
+					Arg::new("json")
+						.long("json")
+						.about("Print the backups as a JSON array instead of a table"),
+				)
+				.arg(
+					Arg::new("reverse")
+						.long("reverse")
+						.about("List the oldest backups first"),
+				),
+		)
+		.subcommand(
+			App::new("prune")
+				.about(
+					"Delete the backups of files that are beyond the limits, without watching them",
+				)
+				.long_about(
+					"Delete the backups of files that are beyond the limits given with \
+					 --max-backups, --keep-for, --max-total-size, and --retention, without \
+					 watching them. When multiple limits are given, backups are deleted until all \
+					 of them are met.\n\nThe same options used to make the backups (like \
+					 --output-dir and --name-template) need to be given for them to be found.",
+				)
+				.arg(
+					Arg::new("file")
+						.required(true)
+						.multiple(true)
+						.index(1)
+						.value_hint(ValueHint::FilePath)
+						.about("The file(s) to prune the backups of"),
+				),
+		);
 
-	// Parse and prepare the config
-	let watch_file = String::from(matches.value_of("watch-file").unwrap());
-	let interval = matches
-		.value_of("interval")
-		.unwrap()
-		.parse::<i64>()
-		.unwrap();
-	let quiet = matches.is_present("quiet");
-	let starting_backup = matches.is_present("starting-backup");
-
-	// Create polling context
-	let mut poll_ctx = PollContext {
-		watch_file,
-		cached_hash: None,
-		quiet,
+	let mut args: Vec<OsString> = env::args_os().collect();
+	// Options set through environment variables come before the ones on the command line, so that
+	// those win, but after the ones in the config file (which are added later)
+	let env_args = watch_env_args(&app).unwrap_or_else(|e| {
+		eprintln!("Invalid environment variable: {}", e);
+		process::exit(1);
+	});
+	args.splice(1..1, env_args.into_iter().map(OsString::from));
+	// A config file in the current directory is used without having to be given
+	let given_config = args
+		.iter()
+		.filter_map(|arg| arg.to_str())
+		.any(|arg| arg == "--config" || arg.starts_with("--config="));
+	if !given_config && Path::new(DEFAULT_CONFIG_FILE).is_file() {
+		args.splice(
+			1..1,
+			[
+				OsString::from("--config"),
+				OsString::from(DEFAULT_CONFIG_FILE),
+			],
+		);
+	}
+	let matches = app.clone().get_matches_from(&args);
+	let all_matches = with_config_file(&app, &args, matches).unwrap_or_else(|e| {
+		eprintln!("{}", e);
+		process::exit(1);
+	});
+	let matches = &all_matches[0];
+	let has_passphrase = set_passphrase(matches).unwrap_or_else(|e| {
+		eprintln!("{}", e);
+		process::exit(1);
+	});
+	if !has_passphrase
+		&& all_matches
+			.iter()
+			.any(|matches| matches.is_present("encrypt"))
+	{
+		eprintln!(
+			"Backups can't be encrypted without a passphrase - give it with --passphrase-file, or \
+			 the WATCH_PASSPHRASE environment variable."
+		);
+		process::exit(1);
+	}
+
+	match matches.subcommand() {
+		Some(("restore", restore_matches)) => process::exit(restore(restore_matches)),
+		Some(("list", list_matches)) => process::exit(list(list_matches)),
+		Some(("prune", prune_matches)) => process::exit(prune(prune_matches)),
+		_ => {}
+	}
+
+	let configs = all_matches
+		.iter()
+		.map(watch_config)
+		.collect::<Result<Vec<_>, _>>()
+		.unwrap_or_else(|e| {
+			eprintln!("{}", e);
+			process::exit(1);
+		});
+
+	if matches.is_present("once") {
+		// The exit code of the target that did the most is used, with errors counting for more than
+		// backups
+		process::exit(configs.into_iter().map(check_once).max().unwrap_or(0));
+	}
+
+	let mut watchers = Vec::new();
+	for (config, matches) in configs.into_iter().zip(&all_matches) {
+		let starting_backup = matches.is_present("starting-backup");
+		let mut watcher = Watcher::new(config).unwrap_or_else(|e| exit_with(&e, 1));
+		watcher.lock_targets().unwrap_or_else(|e| {
+			eprintln!(
+				"{} - stop it first, or give --allow-multiple to watch it anyway.",
+				e
+			);
+			process::exit(1);
+		});
+		watcher
+			.remove_temp_files()
+			.unwrap_or_else(|e| exit_with(&e, 1));
+
+		// If configured to, make a starting backup
+		if !starting_backup {
+			// If we aren't backing up the starting version, then cache the starting hashes of any
+			// files that don't already have a hash from the last run
+			watcher.cache_hashes().unwrap_or_else(|e| exit_with(&e, 1));
+		}
+		if starting_backup || watcher.config().state_file.is_some() {
+			watcher.check_once().unwrap_or_else(|e| exit_with(&e, 1));
+		}
+		watchers.push(watcher);
+	}
+
+	if matches.is_present("detach") {
+		// systemd already runs the watcher in the background, and needs to know which process it is
+		if env::var_os("NOTIFY_SOCKET").is_some() {
+			eprintln!("Running under systemd - carrying on without detaching.");
+		} else if detach() {
+			for watcher in &mut watchers {
+				watcher.detached();
+			}
+		}
+	}
+
+	// Watch until the user is done, either by pressing Enter or by sending a termination signal
+	let daemon = matches.is_present("daemon") || matches.is_present("detach");
+	let target_count = watchers.len();
+	let reload = move || {
+		let configs = app
+			.clone()
+			.try_get_matches_from(&args)
+			.map_err(|e| e.to_string())
+			.and_then(|matches| with_config_file(&app, &args, matches))
+			.and_then(|all_matches| all_matches.iter().map(watch_config).collect());
+		match configs {
+			Ok(configs) if Vec::len(&configs) != target_count => {
+				eprintln!(
+					"Unable to reload the config: the targets can't be added to or removed while \
+					 watching."
+				);
+				None
+			}
+			Ok(configs) => Some(configs),
+			Err(e) => {
+				eprintln!("Unable to reload the config: {}", e);
+				None
+			}
+		}
+	};
+	listen_for_controls(
+		watchers.iter().map(Watcher::controller).collect(),
+		reload,
+		daemon,
+	);
+	// Each target is watched by a watcher of its own, all at once
+	thread::scope(|scope| {
+		for watcher in &mut watchers {
+			scope.spawn(move || watcher.run().unwrap_or_else(|e| exit_with(&e, 1)));
+		}
+	});
+}
+
+/// Adds the options from the config file to the ones given on the command line, if there's a
+/// config file, with one set of options for each of its targets.
+///
+/// The files to watch in the config file are only used if there weren't any given on the command
+/// line, and neither are its targets.
+fn with_config_file(
+	app: &App<'static>,
+	args: &[OsString],
+	matches: ArgMatches,
+) -> Result<Vec<ArgMatches>, String> {
+	let path = match matches
+		.value_of("config")
+		.or_else(|| matches.subcommand().and_then(|(_, m)| m.value_of("config")))
+	{
+		Some(path) => path.to_owned(),
+		None => return Ok(vec![matches]),
+	};
+	let config_file = ConfigFile::load(Path::new(&path))
+		.map_err(|e| format!("Unable to load the config file {}: {}", path, e))?;
+	let mut kinds = option_kinds(app);
+	kinds.remove("config");
+	let invalid = |e: String| format!("Invalid config file {}: {}", path, e);
+	let file_args = config_file
+		.to_args(|name| kinds.get(name).copied())
+		.map_err(invalid)?;
+
+	// The file's options are checked by themselves first, so that problems with them are
+	// reported as being in the file
+	let check = |target_args: &[String]| {
+		let mut file_only = vec![
+			args[0].clone(),
+			OsString::from("--config"),
+			OsString::from(&path),
+		];
+		file_only.extend(file_args.iter().map(OsString::from));
+		file_only.extend(target_args.iter().map(OsString::from));
+		app.clone()
+			.try_get_matches_from(&file_only)
+			.map(|_| ())
+			.map_err(|e| {
+				let message = e.to_string();
+				let first_line = message.lines().next().unwrap_or_default();
+				first_line.trim_start_matches("error: ").to_owned()
+			})
 	};
+	check(&[]).map_err(invalid)?;
 
-	// If configured to, make a starting backup
-	if starting_backup {
-		check_target(&mut poll_ctx);
+	// Each target is watched with the options at the top of the file, then its own, then the ones
+	// on the command line
+	let mut targets = Vec::new();
+	if matches.subcommand().is_none() && !matches.is_present("watch-file") {
+		if !config_file.watch.is_empty() || config_file.target.is_empty() {
+			targets.push((Vec::new(), config_file.watch.clone()));
+		}
+		for (number, target) in config_file.target.iter().enumerate() {
+			let in_target = |e: String| invalid(format!("in target {}, {}", number + 1, e));
+			let target_args = target
+				.to_args(|name| kinds.get(name).copied())
+				.map_err(in_target)?;
+			check(&target_args).map_err(in_target)?;
+			targets.push((target_args, target.watch.clone()));
+		}
 	} else {
-		// If we aren't backing up the starting version, then cache the starting hash
-		poll_ctx.cached_hash = hash_file(&poll_ctx.watch_file)
+		targets.push((Vec::new(), Vec::new()));
+	}
+	targets
+		.into_iter()
+		.map(|(target_args, watch)| {
+			let mut combined = vec![args[0].clone()];
+			combined.extend(file_args.iter().map(OsString::from));
+			combined.extend(target_args.iter().map(OsString::from));
+			combined.extend(watch.iter().map(OsString::from));
+			combined.extend(args[1..].iter().cloned());
+			app.clone()
+				.try_get_matches_from(&combined)
+				.map_err(|e| e.to_string())
+		})
+		.collect()
+}
+
+/// Sets the passphrase to encrypt and decrypt backups with, if there is one, returning whether
+/// there was.
+fn set_passphrase(matches: &ArgMatches) -> Result<bool, String> {
+	let passphrase = match matches.value_of("passphrase-file").or_else(|| {
+		matches
+			.subcommand()
+			.and_then(|(_, m)| m.value_of("passphrase-file"))
+	}) {
+		Some(path) => fs::read_to_string(path)
+			.map(|passphrase| passphrase.trim_end_matches(['\r', '\n']).to_owned())
+			.map_err(|e| format!("Unable to read the passphrase file {}: {}", path, e))?,
+		None => match env::var("WATCH_PASSPHRASE") {
+			Ok(passphrase) => passphrase,
+			Err(_) => return Ok(false),
+		},
+	};
+	if passphrase.is_empty() {
+		return Err(String::from("The passphrase can't be empty."));
+	}
+	encrypt::set_passphrase(passphrase);
+	Ok(true)
+}
+
+/// What kind of option each of the options is, by name.
+fn option_kinds<'a>(app: &'a App<'static>) -> HashMap<&'a str, OptionKind> {
+	app.get_arguments()
+		.filter_map(|arg| {
+			let long = arg.get_long()?;
+			let kind = if arg.is_set(ArgSettings::TakesValue) {
+				OptionKind::Value
+			} else {
+				OptionKind::Flag
+			};
+			Some((long, kind))
+		})
+		.filter(|(long, _)| !["help", "version"].contains(long))
+		.collect()
+}
+
+/// The options set through `WATCH_*` environment variables, as command line arguments.
+///
+/// They're checked by themselves, so that problems with them are reported as being with the
+/// environment variables.
+fn watch_env_args(app: &App<'static>) -> Result<Vec<String>, String> {
+	let env_args = config::env_args(option_kinds(app))?;
+	// The config file given here isn't loaded, it just keeps the files to watch from being needed
+	let mut env_only = vec![
+		String::from(env!("CARGO_PKG_NAME")),
+		format!("--config={}", DEFAULT_CONFIG_FILE),
+	];
+	env_only.extend(env_args.iter().cloned());
+	match app.clone().try_get_matches_from(&env_only) {
+		Err(e) if e.kind != ErrorKind::MissingRequiredArgument => {
+			let message = e.to_string();
+			let first_line = message.lines().next().unwrap_or_default();
+			Err(first_line.trim_start_matches("error: ").to_owned())
+		}
+		_ => Ok(env_args),
 	}
+}
 
-	// Begin polling
-	let timer = Timer::new();
-	let guard = timer.schedule_repeating(Duration::milliseconds(interval), move || {
-		check_target(&mut poll_ctx)
+/// Prepares a config for watching from the options.
+fn watch_config(matches: &ArgMatches) -> Result<WatchConfig, String> {
+	let mut watch_patterns: Vec<String> = matches
+		.values_of("watch-file")
+		.map(|values| values.map(String::from).collect())
+		.unwrap_or_default();
+	for path in matches.values_of("from-file").into_iter().flatten() {
+		let listed = read_target_list(Path::new(path))
+			.map_err(|e| format!("Unable to read the list of files to watch {}: {}", path, e))?;
+		watch_patterns.extend(listed);
+	}
+	if watch_patterns.is_empty() {
+		return Err(String::from(
+			"There are no files to watch - give them on the command line, in a --from-file list, \
+			 or as watch in the config file.",
+		));
+	}
+	let mut config = backup_config(matches, watch_patterns);
+	config.interval = parse_interval(matches.value_of("interval").unwrap())
+		.unwrap()
+		.to_std()
+		.unwrap();
+	if matches.is_present("adaptive") {
+		let adaptive = AdaptiveInterval {
+			min: parse_interval(matches.value_of("min-interval").unwrap_or("500ms"))
+				.unwrap()
+				.to_std()
+				.unwrap(),
+			max: parse_interval(matches.value_of("max-interval").unwrap_or("1m"))
+				.unwrap()
+				.to_std()
+				.unwrap(),
+		};
+		if adaptive.min > adaptive.max {
+			return Err(String::from(
+				"--min-interval can't be longer than --max-interval.",
+			));
+		}
+		config.adaptive = Some(adaptive);
+	}
+	config.log_file = matches.value_of("log-file").map(PathBuf::from);
+	config.log_rotation = matches.value_of("log-max-size").map(|s| LogRotation {
+		max_size: parse_size(s).unwrap(),
+		keep: matches
+			.value_of("log-keep")
+			.map_or(5, |s| s.parse::<usize>().unwrap()),
 	});
+	config.format = match matches.value_of("format").unwrap() {
+		"json" => OutputFormat::Json,
+		_ => OutputFormat::Text,
+	};
+	config.watch_mode = match matches.value_of("watch-mode").unwrap() {
+		"poll" => WatchMode::Poll,
+		_ => WatchMode::Events,
+	};
+	config.debounce = parse_interval(matches.value_of("debounce").unwrap())
+		.unwrap()
+		.to_std()
+		.unwrap();
+	config.recursive = matches.is_present("recursive");
+	config.excludes = matches
+		.values_of("exclude")
+		.map_or_else(Vec::new, |values| {
+			values.map(|s| ExcludePattern::parse(s).unwrap()).collect()
+		});
+	config.use_gitignore = matches.is_present("use-gitignore");
+	config.fast_path = !matches.is_present("no-fast-path");
+	config.verify = matches.is_present("verify");
+	config.dry_run = matches.is_present("dry-run");
+	config.dedup = matches.is_present("dedup");
+	config.on_change = matches.value_of("on-change").map(String::from);
+	config.on_error = matches.value_of("on-error").map(String::from);
+	config.on_error_interval = parse_interval(matches.value_of("on-error-interval").unwrap())
+		.unwrap()
+		.to_std()
+		.unwrap();
+	config.notify_desktop = matches.is_present("notify-desktop");
+	config.webhook = matches.value_of("webhook").map(|url| Webhook {
+		url: url.to_owned(),
+		headers: matches
+			.values_of("webhook-header")
+			.map_or_else(Vec::new, |values| {
+				values.map(|s| webhook::parse_header(s).unwrap()).collect()
+			}),
+		timeout: matches
+			.value_of("webhook-timeout")
+			.map_or(webhook::DEFAULT_TIMEOUT, |s| {
+				parse_interval(s).unwrap().to_std().unwrap()
+			}),
+	});
+	config.s3 = matches.value_of("s3").map(S3Bucket::from_env).transpose()?;
+	config.s3_delete_local = matches.is_present("s3-delete-local");
+	config.sftp = matches.value_of("sftp").map(|s| SftpDestination {
+		key: matches.value_of("sftp-key").map(PathBuf::from),
+		known_hosts: matches.value_of("sftp-known-hosts").map(PathBuf::from),
+		..SftpDestination::parse(s).unwrap()
+	});
+	config.hook_timeout = matches
+		.value_of("hook-timeout")
+		.map(|s| parse_interval(s).unwrap().to_std().unwrap());
+	config.diff = matches.is_present("diff");
+	config.cooldown = matches
+		.value_of("cooldown")
+		.map(|s| parse_interval(s).unwrap().to_std().unwrap());
+	config.settle = matches
+		.value_of("settle")
+		.map(|s| parse_interval(s).unwrap().to_std().unwrap());
+	config.run_for = matches
+		.value_of("run-for")
+		.map(|s| parse_interval(s).unwrap().to_std().unwrap());
+	config.max_changes = matches
+		.value_of("max-changes")
+		.map(|s| s.parse::<usize>().unwrap());
+	config.count_starting_backups = matches.is_present("count-starting-backups");
+	config.fail_if_missing = matches.is_present("fail-if-missing");
+	config.final_backup = matches.is_present("final-backup");
+	config.follow_symlinks = !matches.is_present("no-follow-symlinks");
+	config.on_delete = match matches.value_of("on-delete").unwrap() {
+		_ if matches.is_present("exit-on-delete") => OnDelete::Exit,
+		"exit" => OnDelete::Exit,
+		"backup-last" => OnDelete::BackupLast,
+		_ => OnDelete::Wait,
+	};
+	config.backup_on_delete = matches.is_present("backup-on-delete");
+	config.follow_rename =
+		matches
+			.is_present("follow-rename")
+			.then(|| match matches.value_of("follow") {
+				Some("path") => Follow::Path,
+				_ => Follow::Inode,
+			});
+	config.missing_grace = matches
+		.value_of("missing-grace")
+		.map(|s| s.parse::<usize>().unwrap());
+	config.threads = matches
+		.value_of("threads")
+		.map(|s| s.parse::<usize>().unwrap());
+	config.strict = matches.is_present("strict");
+	config.allow_multiple = matches.is_present("allow-multiple");
+	Ok(config)
+}
+
+/// Prepares a config from the options that are shared with the subcommands, which are all about
+/// where backups are and how they're written.
+fn backup_config(matches: &ArgMatches, watch_patterns: Vec<String>) -> WatchConfig {
+	let mut config = WatchConfig::new(watch_patterns);
+	config.hardlink_dedup = matches.is_present("hardlink-dedup");
+	config.max_backups = matches
+		.value_of("max-backups")
+		.map(|s| s.parse::<usize>().unwrap())
+		.filter(|&n| n > 0);
+	config.keep_for = matches
+		.value_of("keep-for")
+		.map(|s| parse_duration(s).unwrap());
+	config.max_total_size = matches
+		.value_of("max-total-size")
+		.map(|s| parse_size(s).unwrap());
+	config.retention = matches
+		.value_of("retention")
+		.map(|s| RetentionPolicy::parse(s).unwrap());
+	config.output_dir = matches.value_of("output-dir").map(PathBuf::from);
+	config.codec = matches
+		.value_of("compress")
+		.map_or(Codec::Copy, |s| Codec::parse(s).unwrap());
+	config.encrypt = matches.is_present("encrypt");
+	if matches.is_present("delta") {
+		config.delta = Some(
+			matches
+				.value_of("full-every")
+				.unwrap()
+				.parse::<usize>()
+				.unwrap(),
+		);
+	}
+	config.preserve = if matches.is_present("no-preserve") {
+		Preserve::None
+	} else {
+		Preserve::parse(matches.value_of("preserve").unwrap()).unwrap()
+	};
+	config.hash = HashOptions {
+		algorithm: HashAlgorithm::parse(matches.value_of("hash").unwrap()).unwrap(),
+		mmap: if matches.is_present("mmap") {
+			MmapMode::Always
+		} else if matches.is_present("no-mmap") {
+			MmapMode::Never
+		} else {
+			MmapMode::Auto
+		},
+		buffer_size: parse_size(matches.value_of("hash-buffer").unwrap()).unwrap() as usize,
+	};
+	config.lock_retry = RetryPolicy {
+		retries: matches
+			.value_of("lock-retries")
+			.unwrap()
+			.parse::<u32>()
+			.unwrap(),
+		delay: parse_interval(matches.value_of("lock-retry-delay").unwrap())
+			.unwrap()
+			.to_std()
+			.unwrap(),
+	};
+	config.copy_retry = RetryPolicy {
+		retries: matches
+			.value_of("copy-retries")
+			.unwrap()
+			.parse::<u32>()
+			.unwrap(),
+		delay: parse_interval(matches.value_of("copy-retry-delay").unwrap())
+			.unwrap()
+			.to_std()
+			.unwrap(),
+	};
+	config.name_template = NameTemplate::parse(matches.value_of("name-template").unwrap())
+		.unwrap()
+		.with_timestamp_format(
+			TimestampFormat::parse(matches.value_of("timestamp-format").unwrap()).unwrap(),
+		);
+	if matches.is_present("numbering") {
+		config.name_template = config.name_template.with_numbering();
+	}
+	config.manifest = matches.value_of("manifest").map(PathBuf::from);
+	config.local_time = matches.is_present("local");
+	config.state_file = matches.value_of("state-file").map(PathBuf::from);
+	config.quiet = matches.is_present("quiet");
+	config.color = match matches.value_of("color").unwrap() {
+		"always" => ColorChoice::Always,
+		"never" => ColorChoice::Never,
+		_ => ColorChoice::Auto,
+	};
+	config.verbosity = matches.occurrences_of("verbose").min(u8::MAX as u64) as u8;
+	config
+}
 
-	// Wait indefinitely until the user is done
-	io::stdin().read_line(&mut String::new()).unwrap();
+/// Restores a file from one of its backups, returning the exit code to use.
+fn restore(matches: &ArgMatches) -> i32 {
+	let config = backup_config(matches, vec![matches.value_of("file").unwrap().to_owned()]);
+	let mut watcher = Watcher::new(config).unwrap_or_else(|e| exit_with(&e, 1));
+	let dry_run = matches.is_present("dry-run");
+	for target in watcher.resolve_targets() {
+		if let Err(e) = watcher.restore(&target, matches.value_of("at"), dry_run) {
+			exit_with(&e, 1);
+		}
+	}
+	0
+}
 
-	// Stop the scheduled timer (technically this step is unnecessary as it'd happen on exit
-	// anyways, but this way the semantics are clearer and guard doesn't appear useless)
-	drop(guard)
+/// Prunes the backups of files, returning the exit code to use.
+fn prune(matches: &ArgMatches) -> i32 {
+	let config = backup_config(
+		matches,
+		matches
+			.values_of("file")
+			.unwrap()
+			.map(String::from)
+			.collect(),
+	);
+	if config.max_backups.is_none()
+		&& config.keep_for.is_none()
+		&& config.max_total_size.is_none()
+		&& config.retention.is_none()
+	{
+		eprintln!(
+			"Nothing to prune by - give at least one of --max-backups, --keep-for, \
+			 --max-total-size, or --retention."
+		);
+		return 1;
+	}
+	let mut watcher = Watcher::new(config).unwrap_or_else(|e| exit_with(&e, 1));
+	for target in watcher.resolve_targets() {
+		watcher.prune(&target).unwrap_or_else(|e| exit_with(&e, 1));
+	}
+	if watcher.error_count() > 0 {
+		1
+	} else {
+		0
+	}
 }
 
-fn check_target(poll_ctx: &mut PollContext) {
-	// Calculate hash
-	let hash = hash_file(&poll_ctx.watch_file).expect("Unable to hash file");
+/// Lists the backups of a file, returning the exit code to use.
+fn list(matches: &ArgMatches) -> i32 {
+	let config = backup_config(matches, vec![matches.value_of("file").unwrap().to_owned()]);
+	let mut watcher = Watcher::new(config).unwrap_or_else(|e| exit_with(&e, 1));
+	let mut backups = Vec::new();
+	for target in watcher.resolve_targets() {
+		backups.extend(
+			watcher
+				.backups(&target)
+				.unwrap_or_else(|e| exit_with(&e, 1)),
+		);
+	}
+	if !matches.is_present("reverse") {
+		backups.reverse();
+	}
+	let sizes = backups
+		.iter()
+		.map(|backup| fs::metadata(&backup.path).map(|m| m.len()).ok())
+		.collect::<Vec<_>>();
+
+	if matches.is_present("json") {
+		let entries = backups
+			.iter()
+			.zip(&sizes)
+			.map(|(backup, size)| {
+				json!({
+					"timestamp": backup.timestamp,
+					"time": backup.time.map(|time| time.to_rfc3339()),
+					"path": backup.path,
+					"size": size,
+					"hash": backup.hash,
+				})
+			})
+			.collect::<Vec<_>>();
+		println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+		return 0;
+	}
+	if backups.is_empty() {
+		println!(
+			"There are no backups of {}.",
+			matches.value_of("file").unwrap()
+		);
+		return 0;
+	}
+
+	let rows = backups
+		.iter()
+		.zip(&sizes)
+		.map(|(backup, size)| {
+			[
+				backup.timestamp.clone(),
+				size.map_or_else(|| String::from("?"), format_size),
+				backup.hash.clone().unwrap_or_else(|| String::from("-")),
+				backup.path.display().to_string(),
+			]
+		})
+		.collect::<Vec<_>>();
+	let header = [
+		String::from("TIMESTAMP"),
+		String::from("SIZE"),
+		String::from("HASH"),
+		String::from("PATH"),
+	];
+	let widths = (0..header.len())
+		.map(|i| {
+			rows.iter()
+				.chain(Some(&header))
+				.map(|row| row[i].len())
+				.max()
+				.unwrap_or(0)
+		})
+		.collect::<Vec<_>>();
+	for row in Some(&header).into_iter().chain(&rows) {
+		println!(
+			"{:<w0$}  {:>w1$}  {:<w2$}  {}",
+			row[0],
+			row[1],
+			row[2],
+			row[3],
+			w0 = widths[0],
+			w1 = widths[1],
+			w2 = widths[2],
+		);
+	}
+	0
+}
 
-	// Check if the file has changed, and if it has, a backup should be made
-	if poll_ctx.cached_hash == None || poll_ctx.cached_hash.unwrap() != hash {
-		let timestamp = get_timestamp();
+/// Checks for changes a single time, returning the exit code to use.
+///
+/// Files without a hash in the state file are compared against their newest existing backup, so
+/// that this can be run over and over again (like from cron) without making duplicate backups.
+fn check_once(config: WatchConfig) -> i32 {
+	let mut watcher = Watcher::new(config).unwrap_or_else(|e| exit_with(&e, 2));
+	watcher
+		.remove_temp_files()
+		.unwrap_or_else(|e| exit_with(&e, 2));
+	watcher
+		.cache_hashes_from_backups()
+		.unwrap_or_else(|e| exit_with(&e, 2));
+	let backups = watcher.check_once().unwrap_or_else(|e| exit_with(&e, 2));
+	if watcher.error_count() > 0 {
+		2
+	} else if backups.is_empty() {
+		0
+	} else {
+		1
+	}
+}
 
-		if !poll_ctx.quiet {
-			if poll_ctx.cached_hash == None {
-				println!("Making a starting backup. {}: {:#034x}", timestamp, hash);
-			} else {
-				println!("File changed! {}: {:#034x}", timestamp, hash);
+/// Passes the commands entered on stdin along to the watcher, and tells it to shut down when
+/// SIGINT/SIGTERM is received. It's also told to back everything up right away when SIGUSR1 is
+/// received, and to reload its config from the options when SIGHUP is received.
+///
+/// Reaching the end of stdin (like when it isn't attached to a terminal) doesn't count as being
+/// told to quit, so that the program can run unattended until it's signalled. As a daemon, stdin
+/// isn't read at all.
+fn listen_for_controls(
+	controllers: Vec<Controller>,
+	reload: impl Fn() -> Option<Vec<WatchConfig>> + Send + Sync + 'static,
+	daemon: bool,
+) {
+	let controllers = Arc::new(controllers);
+	// Every watcher is told the same thing, apart from their own configs when reloading
+	let send = {
+		let controllers = Arc::clone(&controllers);
+		move |control: fn() -> Control| {
+			for controller in controllers.iter() {
+				controller.send(control());
+			}
+		}
+	};
+	let reload = {
+		let controllers = Arc::clone(&controllers);
+		Arc::new(move || {
+			if let Some(configs) = reload() {
+				for (controller, config) in controllers.iter().zip(configs) {
+					controller.send(Control::Reload(Box::new(config)));
+				}
+			}
+		})
+	};
+	let signalled = AtomicBool::new(false);
+	let shut_down = Arc::new({
+		let send = send.clone();
+		move || {
+			// A second signal means the user doesn't want to wait for the watcher to finish up
+			if signalled.swap(true, Ordering::SeqCst) {
+				eprintln!("Exiting right away.");
+				process::exit(130);
 			}
+			send(|| Control::Shutdown)
 		}
+	});
+	let interrupted = Arc::clone(&shut_down);
+	ctrlc::set_handler(move || interrupted()).expect("Unable to set up the signal handler");
 
-		fs::copy(
-			&poll_ctx.watch_file,
-			format!("{}.{}.bak", poll_ctx.watch_file, timestamp),
-		)
-		.expect("Unable to copy a backup of file");
+	#[cfg(unix)]
+	{
+		use signal_hook::{
+			consts::{SIGHUP, SIGTERM, SIGUSR1},
+			iterator::Signals,
+		};
 
-		poll_ctx.cached_hash = Some(hash);
+		let send = send.clone();
+		let reload = Arc::clone(&reload);
+		let mut signals =
+			Signals::new([SIGTERM, SIGUSR1, SIGHUP]).expect("Unable to set up the signal handler");
+		thread::spawn(move || {
+			for signal in signals.forever() {
+				match signal {
+					SIGTERM => shut_down(),
+					SIGHUP => reload(),
+					_ => send(|| Control::Backup),
+				}
+			}
+		});
 	}
-}
 
-fn hash_file(file_path: &String) -> Option<u128> {
-	let mut hasher = SipHasher::new();
-	match fs::File::open(file_path) {
-		Ok(mut file) => {
-			let mut hash_buffer = [0u8; 4096];
-			loop {
-				match file.read(&mut hash_buffer) {
-					Ok(n) if n > 0 => hasher.write(&hash_buffer),
-					Ok(n) if n == 0 => break,
-					_ => return None,
+	if daemon {
+		return;
+	}
+	thread::spawn(move || {
+		let mut line = String::new();
+		while let Ok(n) = io::stdin().read_line(&mut line) {
+			if n == 0 {
+				break;
+			}
+			match line.trim() {
+				// Just pressing Enter has always been how to stop
+				"" | "quit" | "q" => {
+					send(|| Control::Shutdown);
+					break;
 				}
+				"backup" | "b" => send(|| Control::Backup),
+				"status" => send(|| Control::Status),
+				"pause" => send(|| Control::Pause),
+				"resume" => send(|| Control::Resume),
+				"reload" => reload(),
+				_ => eprintln!(
+					"Commands: status, pause, resume, backup (or b), reload, and quit (or just \
+					 Enter)."
+				),
 			}
-			Some(hasher.finish128().into())
+			line.clear();
+		}
+	});
+}
+
+/// Carries on in a new process in the background, detached from the terminal, while this one
+/// exits, returning whether it did.
+///
+/// Only the current thread carries on in the new process, so the watchers need to be told about
+/// it before they're used again.
+#[cfg(unix)]
+fn detach() -> bool {
+	use std::os::unix::io::AsRawFd;
+
+	// Safety: the only other threads are the ones hashing targets in the watchers' thread pools,
+	// which are idle between checks and let go of in the new process
+	match unsafe { libc::fork() } {
+		-1 => {
+			eprintln!("Unable to detach: {}", io::Error::last_os_error());
+			process::exit(1);
+		}
+		0 => {}
+		pid => {
+			println!("Detached, running as process {}.", pid);
+			process::exit(0);
 		}
-		Err(_) => None,
 	}
+	// Safety: these only affect the process and its standard streams, which nothing else is
+	// using yet
+	unsafe {
+		libc::setsid();
+		// Output goes to the log file from now on, since there's no terminal to print to
+		if let Ok(null) = fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open("/dev/null")
+		{
+			for fd in 0..=2 {
+				libc::dup2(null.as_raw_fd(), fd);
+			}
+		}
+	}
+	true
+}
+
+/// Detaching isn't possible outside of Unix, so this just carries on like a daemon.
+#[cfg(not(unix))]
+fn detach() -> bool {
+	eprintln!("Detaching is only possible on Unix - carrying on without detaching.");
+	false
 }
 
-fn get_timestamp() -> String {
-	let now = Utc::now();
-	format!(
-		"{:04}{:02}{:02}{:02}{:02}{:02}{:03}",
-		now.year(),
-		now.month(),
-		now.day(),
-		now.hour(),
-		now.minute(),
-		now.second(),
-		now.timestamp_subsec_millis()
-	)
+fn exit_with(error: &WatchError, code: i32) -> ! {
+	eprintln!("{}", error);
+	process::exit(code);
 }
@@ -0,0 +1,58 @@
+//! A record of every backup made, kept alongside the backups so that they don't all need to be
+//! hashed again to find out what's in them.
+//!
+//! The manifest has one JSON object per line, so that new entries can be appended without reading
+//! or rewriting what's already there.
+
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, OpenOptions},
+	io::{self, ErrorKind, Write},
+	path::{Path, PathBuf},
+};
+
+/// A backup that was made.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+	pub timestamp: String,
+	/// The file that was backed up
+	pub file: String,
+	pub backup: PathBuf,
+	/// The hash of the backed up content, in hex
+	pub hash: String,
+	/// The algorithm the hash was made with, like `siphash`
+	pub algorithm: String,
+	/// The size of the backed up content, before any compressing
+	pub size: u64,
+}
+
+/// Adds an entry to the end of the manifest, creating it if it doesn't exist yet.
+///
+/// Each entry is written all at once in append mode, so that multiple watchers sharing a manifest
+/// don't mix up each other's entries.
+pub fn append(path: &Path, entry: &ManifestEntry) -> io::Result<()> {
+	let mut line = serde_json::to_vec(entry)?;
+	line.push(b'\n');
+	OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)?
+		.write_all(&line)
+}
+
+/// Loads every entry in the manifest, or none if it doesn't exist yet.
+///
+/// A manifest with any entry that can't be parsed is rejected entirely, since there's no telling
+/// what else is wrong with it.
+pub fn load(path: &Path) -> io::Result<Vec<ManifestEntry>> {
+	let contents = match fs::read_to_string(path) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+	contents
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| serde_json::from_str(line).map_err(io::Error::from))
+		.collect()
+}
@@ -0,0 +1,142 @@
+//! How output is formatted, both for other programs and for people.
+
+use serde::Serialize;
+use std::{env, io::IsTerminal, path::Path};
+
+/// How the watcher reports what it's doing on stdout.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// Messages for people to read
+	Text,
+	/// One JSON object per line for each event, and nothing else
+	Json,
+}
+
+/// Something that happened, as it's reported in JSON output.
+///
+/// Each event is an object with its name in the `event` field, like `"event": "backup_created"`,
+/// alongside the fields of the event. These names are relied on by other programs, so they
+/// shouldn't change.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+	/// The watcher started watching, either for filesystem `events` or by `poll`ing
+	Started { mode: &'a str },
+	/// A file was backed up, either for the first time since starting or because it changed
+	BackupCreated {
+		timestamp: &'a str,
+		file: &'a str,
+		backup: &'a Path,
+		hash: &'a str,
+		/// The size of the file, in bytes
+		size: Option<u64>,
+		/// Whether this is the first backup since starting
+		starting: bool,
+		/// Whether the backup was asked for, rather than being made because of a change
+		manual: bool,
+	},
+	/// A file changed, but its content was already backed up
+	Duplicate {
+		timestamp: &'a str,
+		file: &'a str,
+		backup: &'a Path,
+		hash: &'a str,
+	},
+	/// An old backup was deleted
+	Pruned { file: &'a str, backup: &'a Path },
+	/// A watched file was deleted, which is only reported once until it comes back
+	Deleted {
+		file: &'a str,
+		/// The backup with the file's last content, if it was looked for (with
+		/// `backup_on_delete`) and there is one
+		last_backup: Option<&'a Path>,
+	},
+	/// A file was checked and hadn't changed
+	NoChange { file: &'a str, hash: &'a str },
+	/// Something went wrong, which was reported and moved past
+	Error {
+		kind: &'a str,
+		message: &'a str,
+		file: Option<&'a str>,
+	},
+	/// How a file is doing, when the status is asked for
+	Status {
+		file: &'a str,
+		/// The hash of the file's latest content, if it's been hashed
+		hash: Option<&'a str>,
+		/// How long ago the file was last backed up, if it's been backed up since starting
+		last_backup_ms: Option<u64>,
+		/// How many backups of the file there are
+		backups: usize,
+		paused: bool,
+	},
+	/// A summary of what the watcher did, once it's stopped
+	Summary {
+		ran_for_ms: u64,
+		/// How many times the files were checked
+		checks: usize,
+		/// How many backups were made, including starting backups
+		backups: usize,
+		/// The total size of the backups that were made, in bytes
+		bytes_written: u64,
+		newest_backup: Option<&'a Path>,
+	},
+	/// The watcher stopped, because it was told to (`shutdown`) or reached `run_for` or
+	/// `max_changes`
+	Shutdown { reason: &'a str, changes: usize },
+}
+
+impl Event<'_> {
+	/// The event as a single line of JSON.
+	pub fn to_json(&self) -> String {
+		serde_json::to_string(self).unwrap()
+	}
+}
+
+/// Whether to colour messages printed to a terminal.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+	/// Only when printing to a terminal, and `NO_COLOR` isn't set
+	Auto,
+	Always,
+	Never,
+}
+
+impl ColorChoice {
+	/// Whether to colour what's printed to the stream.
+	pub fn enabled(self, stream: &impl IsTerminal) -> bool {
+		match self {
+			ColorChoice::Auto => {
+				env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && stream.is_terminal()
+			}
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		}
+	}
+}
+
+/// The colours messages are printed in.
+#[derive(Clone, Copy)]
+pub enum Color {
+	/// For backups being made
+	Green,
+	/// For changes that are being held off on
+	Yellow,
+	/// For errors
+	Red,
+	/// For extra detail that's only shown when being verbose
+	Dim,
+}
+
+impl Color {
+	/// Wraps the text in the escape codes for the colour.
+	pub fn paint(self, text: &str) -> String {
+		let code = match self {
+			Color::Green => "32",
+			Color::Yellow => "33",
+			Color::Red => "31",
+			Color::Dim => "2",
+		};
+		format!("\x1b[{}m{}\x1b[0m", code, text)
+	}
+}
@@ -0,0 +1,76 @@
+//! Carrying the metadata of watched files over to their backups.
+
+use filetime::FileTime;
+use std::{fs, io, path::Path};
+
+/// Which metadata of the source file a backup keeps.
+#[derive(Clone, Copy)]
+pub enum Preserve {
+	None,
+	/// The permissions
+	Mode,
+	/// The access and modification times
+	Times,
+	All,
+}
+
+impl Preserve {
+	pub fn parse(s: &str) -> Result<Self, String> {
+		match s {
+			"none" => Ok(Preserve::None),
+			"mode" => Ok(Preserve::Mode),
+			"times" => Ok(Preserve::Times),
+			"all" => Ok(Preserve::All),
+			_ => Err(String::from("must be one of none, mode, times, or all")),
+		}
+	}
+
+	/// Copies the chosen metadata of the source over to the backup.
+	///
+	/// This has to happen once the backup has been fully written, since writing to it would
+	/// update its modification time again.
+	///
+	/// With everything being preserved on Unix, the owner is carried over too when allowed to (like
+	/// when running as root).
+	pub fn apply(&self, source_metadata: &fs::Metadata, backup_path: &Path) -> io::Result<()> {
+		#[cfg(unix)]
+		if matches!(self, Preserve::All) {
+			preserve_owner(source_metadata, backup_path)?;
+		}
+		if matches!(self, Preserve::Mode | Preserve::All) {
+			fs::set_permissions(backup_path, source_metadata.permissions())?;
+		}
+		if matches!(self, Preserve::Times | Preserve::All) {
+			filetime::set_file_times(
+				backup_path,
+				FileTime::from_last_access_time(source_metadata),
+				FileTime::from_last_modification_time(source_metadata),
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// Gives the backup the same owner as the source, if it's allowed to be changed.
+///
+/// This is done before anything else, since changing the owner can clear the setuid and setgid
+/// bits.
+#[cfg(unix)]
+fn preserve_owner(source_metadata: &fs::Metadata, backup_path: &Path) -> io::Result<()> {
+	use std::os::unix::fs::{self as unix_fs, MetadataExt};
+
+	let backup_metadata = fs::metadata(backup_path)?;
+	if backup_metadata.uid() == source_metadata.uid()
+		&& backup_metadata.gid() == source_metadata.gid()
+	{
+		return Ok(());
+	}
+	match unix_fs::chown(
+		backup_path,
+		Some(source_metadata.uid()),
+		Some(source_metadata.gid()),
+	) {
+		Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+		result => result,
+	}
+}
@@ -0,0 +1,133 @@
+//! Tiered retention policies, which thin out backups more and more as they get older.
+
+use crate::duration::parse_duration;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+
+/// The names that can be used for how often to keep backups in a tier, along with the period each
+/// one stands for.
+const PERIODS: &[(&str, i64)] = &[
+	("hourly", 60 * 60 * 1000),
+	("daily", 24 * 60 * 60 * 1000),
+	("weekly", 7 * 24 * 60 * 60 * 1000),
+	("monthly", 30 * 24 * 60 * 60 * 1000),
+];
+
+/// How many of the backups in a tier are kept.
+#[derive(Clone, Copy)]
+pub enum Keep {
+	All,
+	/// Only the newest backup in each period of this length
+	Every(Duration),
+}
+
+/// A tier of a retention policy, covering backups up to a certain age.
+#[derive(Clone, Copy)]
+pub struct Tier {
+	pub max_age: Duration,
+	pub keep: Keep,
+}
+
+/// A set of tiers, like keeping every backup from the last day, then one per day for a week, then
+/// one per week for a month.
+///
+/// Backups older than every tier are deleted, except for the newest backup, which is always kept.
+#[derive(Clone)]
+pub struct RetentionPolicy {
+	/// The tiers, from the youngest backups to the oldest
+	tiers: Vec<Tier>,
+}
+
+impl RetentionPolicy {
+	/// Parses a policy of the form `<age>:<keep>,...`, like `24h:all,7d:daily,30d:weekly`.
+	///
+	/// The kept backups can be `all`, `hourly`, `daily`, `weekly`, `monthly`, or a duration like
+	/// `6h`.
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let mut tiers = Vec::new();
+		for tier in s.split(',') {
+			let (max_age, keep) = tier.split_once(':').ok_or_else(|| {
+				format!(
+					"invalid tier '{}' (expected something like 24h:all or 7d:daily)",
+					tier
+				)
+			})?;
+			let max_age = parse_duration(max_age)?;
+			let keep = match keep.trim() {
+				"all" => Keep::All,
+				keep => Keep::Every(
+					PERIODS
+						.iter()
+						.find(|(name, _)| *name == keep)
+						.map(|(_, millis)| Ok(Duration::milliseconds(*millis)))
+						.unwrap_or_else(|| parse_duration(keep))
+						.map_err(|_| {
+							format!(
+								"invalid tier '{}' (the backups to keep must be all, hourly, \
+								 daily, weekly, monthly, or a duration like 6h)",
+								tier
+							)
+						})?,
+				),
+			};
+			if let Keep::Every(period) = keep {
+				if period <= Duration::zero() {
+					return Err(format!(
+						"invalid tier '{}' (the period must be greater than 0)",
+						tier
+					));
+				}
+			}
+			if tiers
+				.last()
+				.is_some_and(|last: &Tier| last.max_age >= max_age)
+			{
+				return Err(String::from(
+					"the tiers must be in order of increasing age, like 24h:all,7d:daily",
+				));
+			}
+			tiers.push(Tier { max_age, keep });
+		}
+		Ok(Self { tiers })
+	}
+
+	/// Works out which of the backups made at the given times should be kept, in the same order.
+	///
+	/// Backups without a time are always kept.
+	pub fn select(&self, times: &[Option<DateTime<Utc>>], now: DateTime<Utc>) -> Vec<bool> {
+		let newest = times.iter().flatten().max().copied();
+		let mut kept_periods = HashSet::new();
+		let mut keep = vec![true; times.len()];
+
+		// Going from newest to oldest means the newest backup in each period is the one kept
+		let mut order = (0..times.len())
+			.filter_map(|i| times[i].map(|time| (time, i)))
+			.collect::<Vec<_>>();
+		order.sort_by(|a, b| b.cmp(a));
+		for (time, i) in order {
+			if Some(time) == newest {
+				continue;
+			}
+			let age = now - time;
+			let tier = match self.tiers.iter().position(|tier| age <= tier.max_age) {
+				Some(tier) => tier,
+				None => {
+					keep[i] = false;
+					continue;
+				}
+			};
+			keep[i] = match self.tiers[tier].keep {
+				Keep::All => true,
+				Keep::Every(period) => {
+					let period = (
+						tier,
+						time.timestamp_millis()
+							.div_euclid(period.num_milliseconds()),
+					);
+					kept_periods.insert(period)
+				}
+			};
+		}
+		keep
+	}
+}
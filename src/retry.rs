@@ -0,0 +1,64 @@
+//! Retrying operations that fail because another program has a file locked.
+
+use std::{
+	io::{self, ErrorKind},
+	thread,
+	time::Duration,
+};
+
+/// Windows' `ERROR_SHARING_VIOLATION`, for files opened by another program without sharing.
+const ERROR_SHARING_VIOLATION: i32 = 32;
+
+/// Windows' `ERROR_LOCK_VIOLATION`, for files with a region locked by another program.
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// How many times to retry, and how long to wait before the first retry.
+///
+/// The delay doubles with every retry after that.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+	pub retries: u32,
+	pub delay: Duration,
+}
+
+impl RetryPolicy {
+	/// Runs the operation, retrying it while it fails with what looks like a locked file.
+	pub fn run<T>(&self, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+		let mut delay = self.delay;
+		for _ in 0..self.retries {
+			match operation() {
+				Err(e) if is_lock_error(&e) => {
+					thread::sleep(delay);
+					delay = delay.saturating_mul(2);
+				}
+				result => return result,
+			}
+		}
+		operation()
+	}
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			retries: 3,
+			delay: Duration::from_millis(100),
+		}
+	}
+}
+
+/// Whether the error could be caused by another program having the file locked.
+///
+/// Windows reports some locked files as access being denied, but elsewhere that always means the
+/// permissions are wrong, which retrying won't fix.
+fn is_lock_error(error: &io::Error) -> bool {
+	if cfg!(windows)
+		&& (matches!(
+			error.raw_os_error(),
+			Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+		) || error.kind() == ErrorKind::PermissionDenied)
+	{
+		return true;
+	}
+	error.kind() == ErrorKind::WouldBlock
+}
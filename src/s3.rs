@@ -0,0 +1,283 @@
+//! Uploading backups to an S3-compatible bucket, to keep copies of them somewhere else.
+//!
+//! Requests are signed with AWS Signature Version 4, with the credentials, region and endpoint
+//! taken from the standard AWS environment variables so that the same setup works for AWS itself
+//! and for other S3-compatible services (like MinIO).
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::{env, fs::File, io, path::Path, thread, time::Duration};
+
+/// How many times an upload that fails is tried again.
+const RETRIES: u32 = 2;
+
+/// How long to wait before the first retry, which doubles with every retry after that.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long an upload can go without any progress before it's given up on.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The region used when one isn't set.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// The payload hash that's signed in place of hashing the whole backup before it's sent.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// A bucket (and a prefix inside it) to upload backups to, along with how to reach it.
+#[derive(Clone)]
+pub struct S3Bucket {
+	pub bucket: String,
+	/// What's put in front of the name of each backup, without a trailing `/`
+	pub prefix: String,
+	/// Something like `https://s3.us-east-1.amazonaws.com`, without a trailing `/`
+	pub endpoint: String,
+	pub region: String,
+	pub access_key: String,
+	pub secret_key: String,
+	/// The token that goes with temporary credentials, if they are
+	pub session_token: Option<String>,
+}
+
+impl S3Bucket {
+	/// Sets up uploading to the location (of the form `bucket/prefix`), with everything else coming
+	/// from the environment:
+	///
+	/// - `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` (and `AWS_SESSION_TOKEN`, if there is one)
+	/// - `AWS_REGION` or `AWS_DEFAULT_REGION`, which is `us-east-1` if neither is set
+	/// - `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL`, which is AWS's endpoint for the region if
+	///   neither is set
+	pub fn from_env(location: &str) -> Result<Self, String> {
+		let (bucket, prefix) = parse_location(location)?;
+		let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+		let (access_key, secret_key) =
+			match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+				(Some(access_key), Some(secret_key)) => (access_key, secret_key),
+				_ => {
+					return Err(String::from(
+						"Backups can't be uploaded to S3 without credentials - set them with the \
+						 AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables.",
+					))
+				}
+			};
+		let region = var("AWS_REGION")
+			.or_else(|| var("AWS_DEFAULT_REGION"))
+			.unwrap_or_else(|| DEFAULT_REGION.to_owned());
+		let endpoint = var("AWS_ENDPOINT_URL_S3")
+			.or_else(|| var("AWS_ENDPOINT_URL"))
+			.unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+		if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+			return Err(format!(
+				"The S3 endpoint {} should start with http:// or https://.",
+				endpoint
+			));
+		}
+		Ok(Self {
+			bucket,
+			prefix,
+			endpoint: endpoint.trim_end_matches('/').to_owned(),
+			region,
+			access_key,
+			secret_key,
+			session_token: var("AWS_SESSION_TOKEN"),
+		})
+	}
+
+	/// The key an object with the name goes under, which has the prefix in front of it.
+	pub fn key_for(&self, name: &str) -> String {
+		if self.prefix.is_empty() {
+			name.to_owned()
+		} else {
+			format!("{}/{}", self.prefix, name)
+		}
+	}
+
+	/// Uploads the file under the key, retrying a couple of times if it doesn't work.
+	///
+	/// Any response other than a success counts as not working.
+	pub fn upload(&self, path: &Path, key: &str) -> Result<(), String> {
+		let agent = ureq::AgentBuilder::new()
+			.timeout_read(TIMEOUT)
+			.timeout_write(TIMEOUT)
+			.build();
+		let mut delay = RETRY_DELAY;
+		let mut attempt = 0;
+		loop {
+			match self.put(&agent, path, key) {
+				Ok(()) => return Ok(()),
+				Err(e) if attempt >= RETRIES => return Err(e),
+				Err(_) => {
+					thread::sleep(delay);
+					delay = delay.saturating_mul(2);
+					attempt += 1;
+				}
+			}
+		}
+	}
+
+	fn put(&self, agent: &ureq::Agent, path: &Path, key: &str) -> Result<(), String> {
+		let file = File::open(path).map_err(|e| e.to_string())?;
+		let length = file.metadata().map_err(|e| e.to_string())?.len();
+		let (host, base_path) = split_endpoint(&self.endpoint);
+		let uri = format!(
+			"{}/{}/{}",
+			base_path,
+			uri_encode(&self.bucket),
+			uri_encode(key)
+		);
+		let now = Utc::now();
+		let date = now.format("%Y%m%d").to_string();
+		let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+		let mut headers = vec![
+			("host", host.to_owned()),
+			("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_owned()),
+			("x-amz-date", amz_date.clone()),
+		];
+		if let Some(token) = &self.session_token {
+			headers.push(("x-amz-security-token", token.clone()));
+		}
+		let authorization = self.authorization("PUT", &uri, &headers, &date, &amz_date);
+
+		let mut request = agent
+			.put(&format!("{}{}", scheme_and_host(&self.endpoint), uri))
+			.set("Content-Length", &length.to_string())
+			.set("Authorization", &authorization);
+		for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+			request = request.set(name, value);
+		}
+		match request.send(io::BufReader::new(file)) {
+			Ok(_) => Ok(()),
+			Err(ureq::Error::Status(status, response)) => {
+				// S3 says what went wrong in an XML body, like `<Code>AccessDenied</Code>`
+				let body = response.into_string().unwrap_or_default();
+				match xml_element(&body, "Code") {
+					Some(code) => Err(format!("status code {}: {}", status, code)),
+					None => Err(format!("status code {}", status)),
+				}
+			}
+			Err(e) => Err(e.to_string()),
+		}
+	}
+
+	/// The `Authorization` header for a request with the headers (which have to be in order by
+	/// name, and lowercase).
+	fn authorization(
+		&self,
+		method: &str,
+		uri: &str,
+		headers: &[(&str, String)],
+		date: &str,
+		amz_date: &str,
+	) -> String {
+		let signed_headers = headers
+			.iter()
+			.map(|(name, _)| *name)
+			.collect::<Vec<_>>()
+			.join(";");
+		let canonical_headers: String = headers
+			.iter()
+			.map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+			.collect();
+		let canonical_request = format!(
+			"{}\n{}\n\n{}\n{}\n{}",
+			method, uri, canonical_headers, signed_headers, UNSIGNED_PAYLOAD
+		);
+		let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+		let string_to_sign = format!(
+			"AWS4-HMAC-SHA256\n{}\n{}\n{}",
+			amz_date,
+			scope,
+			hex(&Sha256::digest(canonical_request.as_bytes()))
+		);
+		let key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+			hmac(
+				format!("AWS4{}", self.secret_key).as_bytes(),
+				date.as_bytes(),
+			),
+			|key, part| hmac(&key, part.as_bytes()),
+		);
+		format!(
+			"AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+			self.access_key,
+			scope,
+			signed_headers,
+			hex(&hmac(&key, string_to_sign.as_bytes()))
+		)
+	}
+}
+
+/// Splits a location of the form `bucket/prefix` (where the prefix is optional) into the bucket
+/// and the prefix.
+pub fn parse_location(s: &str) -> Result<(String, String), String> {
+	let s = s.strip_prefix("s3://").unwrap_or(s);
+	let (bucket, prefix) = s.split_once('/').unwrap_or((s, ""));
+	if bucket.is_empty() {
+		return Err(format!(
+			"invalid S3 location '{}' (expected something like 'my-bucket/backups')",
+			s
+		));
+	}
+	Ok((bucket.to_owned(), prefix.trim_matches('/').to_owned()))
+}
+
+/// Splits the endpoint into its host (with the port, if there is one) and the path after it.
+fn split_endpoint(endpoint: &str) -> (&str, &str) {
+	let rest = endpoint
+		.split_once("://")
+		.map_or(endpoint, |(_, rest)| rest);
+	match rest.find('/') {
+		Some(index) => rest.split_at(index),
+		None => (rest, ""),
+	}
+}
+
+/// The endpoint without any path after the host.
+fn scheme_and_host(endpoint: &str) -> &str {
+	let (_, path) = split_endpoint(endpoint);
+	&endpoint[..endpoint.len() - path.len()]
+}
+
+/// Percent-encodes everything in the path but unreserved characters and the `/`s between its
+/// parts, the way S3 expects it in a signature.
+fn uri_encode(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+	for byte in path.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+				encoded.push(byte as char)
+			}
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+	encoded
+}
+
+/// HMAC-SHA256 of the data with the key.
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+	const BLOCK_SIZE: usize = 64;
+
+	let mut block = [0; BLOCK_SIZE];
+	if key.len() > BLOCK_SIZE {
+		block[..32].copy_from_slice(&Sha256::digest(key));
+	} else {
+		block[..key.len()].copy_from_slice(key);
+	}
+	let mut inner = Sha256::new();
+	inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+	inner.update(data);
+	let mut outer = Sha256::new();
+	outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+	outer.update(inner.finalize());
+	outer.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The text inside the first element with the name, in a simple XML document.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+	let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+	let end = start + xml[start..].find(&format!("</{}>", name))?;
+	Some(&xml[start..end])
+}
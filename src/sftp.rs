@@ -0,0 +1,256 @@
+//! Sending backups to a remote host over SFTP, to keep copies of them somewhere else.
+//!
+//! The connection is kept open between backups, and made again whenever it's dropped. The host
+//! has to be in the known hosts file already (like after connecting to it with `ssh` once), so
+//! backups are never sent to a host pretending to be it.
+
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::{
+	env,
+	fmt::{self, Display, Formatter},
+	fs::File,
+	io::{self, BufReader},
+	net::{TcpStream, ToSocketAddrs},
+	path::{Path, PathBuf},
+	thread,
+	time::Duration,
+};
+
+/// How many times an upload that fails is tried again, connecting again each time.
+const RETRIES: u32 = 2;
+
+/// How long to wait before the first retry, which doubles with every retry after that.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long connecting (or any one step of an upload) can take before it's given up on.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The port SSH is on when one isn't given.
+const DEFAULT_PORT: u16 = 22;
+
+/// What's added to the name of a backup while it's being uploaded, so that a backup that's only
+/// partly there is never mistaken for a whole one.
+const PARTIAL_EXTENSION: &str = ".part";
+
+/// A directory on a remote host to send backups to, along with how to log in to it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SftpDestination {
+	pub user: String,
+	pub host: String,
+	pub port: u16,
+	/// The directory to put backups in, which is relative to the user's home directory unless
+	/// it starts with a `/`
+	pub dir: String,
+	/// The private key to log in with, instead of asking the SSH agent
+	pub key: Option<PathBuf>,
+	/// The known hosts file to check the host against, instead of `~/.ssh/known_hosts`
+	pub known_hosts: Option<PathBuf>,
+}
+
+impl SftpDestination {
+	/// Parses a destination of the form `[user@]host:path` or `sftp://[user@]host[:port]/path`,
+	/// where the user is the current one if it isn't given.
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let invalid = || {
+			format!(
+				"invalid SFTP destination '{}' (expected something like 'user@host:backups')",
+				s
+			)
+		};
+		let (user_host, port, dir) = match s.strip_prefix("sftp://") {
+			Some(rest) => {
+				let (authority, dir) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+				match authority.rsplit_once(':') {
+					Some((user_host, port)) => {
+						(user_host, port.parse().map_err(|_| invalid())?, dir)
+					}
+					None => (authority, DEFAULT_PORT, dir),
+				}
+			}
+			None => {
+				let (user_host, dir) = s.split_once(':').ok_or_else(invalid)?;
+				(user_host, DEFAULT_PORT, dir)
+			}
+		};
+		let (user, host) = match user_host.rsplit_once('@') {
+			Some((user, host)) => (user.to_owned(), host),
+			None => (
+				env::var("USER")
+					.or_else(|_| env::var("USERNAME"))
+					.map_err(|_| invalid())?,
+				user_host,
+			),
+		};
+		if user.is_empty() || host.is_empty() {
+			return Err(invalid());
+		}
+		Ok(Self {
+			user,
+			host: host.to_owned(),
+			port,
+			dir: dir.to_owned(),
+			key: None,
+			known_hosts: None,
+		})
+	}
+
+	/// Where the backup with the name goes on the remote host.
+	fn remote_path(&self, name: &str) -> String {
+		match self.dir.trim_end_matches('/') {
+			"" if self.dir.starts_with('/') => format!("/{}", name),
+			"" => name.to_owned(),
+			dir => format!("{}/{}", dir, name),
+		}
+	}
+
+	fn connect(&self) -> Result<Sftp, String> {
+		let address = (self.host.as_str(), self.port)
+			.to_socket_addrs()
+			.map_err(|e| e.to_string())?
+			.next()
+			.ok_or_else(|| format!("unable to find {}", self.host))?;
+		let stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+		let mut session = Session::new().map_err(|e| e.to_string())?;
+		session.set_tcp_stream(stream);
+		session.set_timeout(TIMEOUT.as_millis() as u32);
+		session.handshake().map_err(|e| e.to_string())?;
+		self.check_host_key(&session)?;
+
+		match &self.key {
+			Some(key) => session.userauth_pubkey_file(&self.user, None, key, None),
+			None => session.userauth_agent(&self.user),
+		}
+		.map_err(|e| format!("unable to log in as {}: {}", self.user, e))?;
+		if !session.authenticated() {
+			return Err(format!("unable to log in as {}", self.user));
+		}
+		session.sftp().map_err(|e| e.to_string())
+	}
+
+	/// Makes sure the host is the one in the known hosts file.
+	fn check_host_key(&self, session: &Session) -> Result<(), String> {
+		let path = match &self.known_hosts {
+			Some(path) => path.clone(),
+			None => env::var_os("HOME")
+				.or_else(|| env::var_os("USERPROFILE"))
+				.map(|home| Path::new(&home).join(".ssh").join("known_hosts"))
+				.ok_or("unable to find the known hosts file")?,
+		};
+		let mut known_hosts = session.known_hosts().map_err(|e| e.to_string())?;
+		known_hosts
+			.read_file(&path, KnownHostFileKind::OpenSSH)
+			.map_err(|e| {
+				format!(
+					"unable to read the known hosts file {}: {}",
+					path.display(),
+					e
+				)
+			})?;
+		let (key, _) = session.host_key().ok_or("the host didn't send its key")?;
+		match known_hosts.check_port(&self.host, self.port, key) {
+			CheckResult::Match => Ok(()),
+			CheckResult::NotFound => Err(format!(
+				"{} isn't in the known hosts file {} (connecting to it with ssh first adds it)",
+				self.host,
+				path.display()
+			)),
+			CheckResult::Mismatch => Err(format!(
+				"the key of {} doesn't match the one in the known hosts file {}, so it may not be \
+				 the host it says it is",
+				self.host,
+				path.display()
+			)),
+			CheckResult::Failure => Err(String::from("unable to check the host's key")),
+		}
+	}
+}
+
+impl Display for SftpDestination {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		if self.port == DEFAULT_PORT {
+			write!(f, "{}@{}:{}", self.user, self.host, self.dir)
+		} else {
+			write!(
+				f,
+				"sftp://{}@{}:{}{}",
+				self.user, self.host, self.port, self.dir
+			)
+		}
+	}
+}
+
+/// Sends backups to a destination, keeping the connection to it open in between.
+pub struct SftpUploader {
+	pub destination: SftpDestination,
+	connection: Option<Sftp>,
+}
+
+impl SftpUploader {
+	/// Sets up sending backups to the destination, which isn't connected to until the first one.
+	pub fn new(destination: SftpDestination) -> Self {
+		Self {
+			destination,
+			connection: None,
+		}
+	}
+
+	/// Sends the file to the destination under the name (which can have directories in it, which
+	/// are made if they aren't there), returning where it ended up.
+	///
+	/// If it can't be sent, the connection is made again and it's tried again a couple of times.
+	pub fn upload(&mut self, path: &Path, name: &str) -> Result<String, String> {
+		let remote_path = self.destination.remote_path(name);
+		let mut delay = RETRY_DELAY;
+		let mut attempt = 0;
+		loop {
+			match self.try_upload(path, &remote_path) {
+				Ok(()) => return Ok(remote_path),
+				Err(e) => {
+					// Whatever went wrong may have been the connection dropping
+					self.connection = None;
+					if attempt >= RETRIES {
+						return Err(e);
+					}
+					thread::sleep(delay);
+					delay = delay.saturating_mul(2);
+					attempt += 1;
+				}
+			}
+		}
+	}
+
+	fn try_upload(&mut self, path: &Path, remote_path: &str) -> Result<(), String> {
+		if self.connection.is_none() {
+			self.connection = Some(self.destination.connect()?);
+		}
+		let sftp = self.connection.as_ref().unwrap();
+		if let Some((dir, _)) = remote_path.rsplit_once('/') {
+			make_dirs(sftp, dir).map_err(|e| e.to_string())?;
+		}
+
+		let partial_path = format!("{}{}", remote_path, PARTIAL_EXTENSION);
+		let mut source = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+		let mut remote_file = sftp
+			.create(Path::new(&partial_path))
+			.map_err(|e| e.to_string())?;
+		io::copy(&mut source, &mut remote_file).map_err(|e| e.to_string())?;
+		drop(remote_file);
+		sftp.rename(
+			Path::new(&partial_path),
+			Path::new(remote_path),
+			Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE),
+		)
+		.map_err(|e| e.to_string())
+	}
+}
+
+/// Makes the remote directory, along with any directories it's in that aren't there.
+fn make_dirs(sftp: &Sftp, dir: &str) -> Result<(), ssh2::Error> {
+	if dir.is_empty() || sftp.stat(Path::new(dir)).is_ok() {
+		return Ok(());
+	}
+	if let Some((parent, _)) = dir.rsplit_once('/') {
+		make_dirs(sftp, parent)?;
+	}
+	sftp.mkdir(Path::new(dir), 0o755)
+}
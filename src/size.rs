@@ -0,0 +1,51 @@
+//! Parsing of human-friendly sizes like `500M` or `20G`.
+
+/// The suffixes accepted in sizes, along with how many bytes each one is.
+const SUFFIXES: &[(&str, u64)] = &[
+	("", 1),
+	("B", 1),
+	("K", 1 << 10),
+	("M", 1 << 20),
+	("G", 1 << 30),
+	("T", 1 << 40),
+];
+
+/// Formats a size in bytes in the largest unit it's at least 1 of, like `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+	let (suffix, multiplier) = SUFFIXES[2..]
+		.iter()
+		.rev()
+		.find(|(_, multiplier)| bytes >= *multiplier)
+		.copied()
+		.unwrap_or(("", 1));
+	if multiplier == 1 {
+		format!("{} B", bytes)
+	} else {
+		format!("{:.1} {}iB", bytes as f64 / multiplier as f64, suffix)
+	}
+}
+
+/// Parses a size in bytes, optionally suffixed with K, M, G, or T (in powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+	let invalid = || {
+		format!(
+			"invalid size '{}' (expected something like 4096, 500M, or 20G)",
+			s
+		)
+	};
+
+	let s = s.trim();
+	let digits_len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let value = s[..digits_len].parse::<u64>().map_err(|_| invalid())?;
+	let suffix = s[digits_len..].trim().to_ascii_uppercase();
+	let suffix = suffix
+		.strip_suffix("IB")
+		.or_else(|| suffix.strip_suffix('B').filter(|s| !s.is_empty()))
+		.unwrap_or(&suffix);
+	let multiplier = SUFFIXES
+		.iter()
+		.find(|(unit, _)| *unit == suffix)
+		.map(|(_, multiplier)| *multiplier)
+		.ok_or_else(invalid)?;
+	value.checked_mul(multiplier).ok_or_else(invalid)
+}
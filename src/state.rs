@@ -0,0 +1,45 @@
+//! State that's persisted between runs, so changes made while the program wasn't running are
+//! still noticed.
+
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::{self, ErrorKind},
+	path::Path,
+};
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct State {
+	/// The last known state of each watched file, by path
+	pub files: BTreeMap<String, FileState>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileState {
+	/// The hash of the file's contents, in hex
+	pub hash: String,
+	/// When the hash was recorded
+	pub timestamp: String,
+}
+
+impl State {
+	/// Loads the state from the file, or starts out empty if it doesn't exist yet.
+	pub fn load(path: &Path) -> io::Result<Self> {
+		match fs::read(path) {
+			Ok(contents) => serde_json::from_slice(&contents)
+				.map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Saves the state to the file, replacing it all at once so it's never left half-written.
+	pub fn save(&self, path: &Path) -> io::Result<()> {
+		let contents = serde_json::to_vec_pretty(self)?;
+		let mut temp_path = path.as_os_str().to_owned();
+		temp_path.push(".tmp");
+		fs::write(&temp_path, contents)?;
+		fs::rename(&temp_path, path)
+	}
+}
@@ -0,0 +1,77 @@
+//! A content-addressed store of backups, so that backups with the same content can be hardlinks
+//! to a single copy rather than each being a copy of their own.
+//!
+//! Each object in the store is a hardlink to the first backup made with its content, named after
+//! the content's hash. Objects that nothing else links to anymore are removed.
+
+use std::{
+	fs,
+	io::{self, ErrorKind},
+	path::{Path, PathBuf},
+};
+
+/// The name of the directory the store is kept in.
+pub const STORE_DIR: &str = ".watch-store";
+
+pub struct Store {
+	pub dir: PathBuf,
+}
+
+impl Store {
+	/// The store kept inside the directory.
+	pub fn in_dir(dir: &Path) -> Self {
+		Self {
+			dir: dir.join(STORE_DIR),
+		}
+	}
+
+	/// Where the object for content with the given hash would be, if it's in the store.
+	///
+	/// The extension is part of the name, so that backups written differently (like compressed
+	/// ones) are never mixed up with each other.
+	pub fn object_path(&self, algorithm: &str, hash: &str, extension: &str) -> PathBuf {
+		self.dir
+			.join(format!("{}-{}{}", algorithm, hash, extension))
+	}
+
+	/// Adds a backup to the store as the object for its content, unless there already is one.
+	pub fn add(&self, object: &Path, backup: &Path) -> io::Result<()> {
+		fs::create_dir_all(&self.dir)?;
+		match fs::hard_link(backup, object) {
+			Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e),
+			_ => Ok(()),
+		}
+	}
+
+	/// Removes the objects that no backups are linked to anymore, returning their paths.
+	///
+	/// Link counts aren't available outside of Unix, so objects are only ever removed there.
+	pub fn remove_unused(&self) -> io::Result<Vec<PathBuf>> {
+		let entries = match fs::read_dir(&self.dir) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		let mut removed = Vec::new();
+		for entry in entries {
+			let entry = entry?;
+			if is_unused(&entry.metadata()?) {
+				fs::remove_file(entry.path())?;
+				removed.push(entry.path());
+			}
+		}
+		Ok(removed)
+	}
+}
+
+#[cfg(unix)]
+fn is_unused(metadata: &fs::Metadata) -> bool {
+	use std::os::unix::fs::MetadataExt;
+
+	metadata.is_file() && metadata.nlink() == 1
+}
+
+#[cfg(not(unix))]
+fn is_unused(_metadata: &fs::Metadata) -> bool {
+	false
+}
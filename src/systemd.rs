@@ -0,0 +1,101 @@
+//! Keeping systemd up to date on how the watcher is doing, when it's running as a `Type=notify`
+//! service.
+//!
+//! Everything here does nothing when the watcher isn't running under systemd (that is, when
+//! `NOTIFY_SOCKET` isn't set).
+
+use std::{
+	cell::Cell,
+	env,
+	time::{Duration, Instant},
+};
+
+/// A connection to systemd's notification socket.
+pub struct Systemd {
+	#[cfg(unix)]
+	socket: std::os::unix::net::UnixDatagram,
+	/// How often systemd needs to hear from the watcher to know it isn't stuck, if it's checking
+	watchdog: Option<Duration>,
+	last_ping: Cell<Instant>,
+}
+
+impl Systemd {
+	/// Connects to systemd's notification socket, if the watcher is running under systemd.
+	///
+	/// A socket that can't be connected to is treated the same as there not being one, since
+	/// the watcher works just as well without systemd knowing how it's doing.
+	#[cfg(unix)]
+	pub fn from_env() -> Option<Self> {
+		use std::os::unix::net::UnixDatagram;
+
+		let path = env::var_os("NOTIFY_SOCKET")?;
+		let socket = UnixDatagram::unbound().ok()?;
+		match path.to_str().and_then(|path| path.strip_prefix('@')) {
+			#[cfg(target_os = "linux")]
+			Some(name) => {
+				use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+				let address = SocketAddr::from_abstract_name(name).ok()?;
+				socket.connect_addr(&address).ok()?;
+			}
+			_ => socket.connect(&path).ok()?,
+		}
+		Some(Self {
+			socket,
+			watchdog: watchdog_interval(),
+			last_ping: Cell::new(Instant::now()),
+		})
+	}
+
+	/// There's no systemd outside of Unix.
+	#[cfg(not(unix))]
+	pub fn from_env() -> Option<Self> {
+		None
+	}
+
+	/// Lets systemd know that the watcher is up and running.
+	pub fn ready(&self) {
+		self.notify("READY=1");
+	}
+
+	/// Lets systemd know that the watcher is shutting down.
+	pub fn stopping(&self) {
+		self.notify("STOPPING=1");
+	}
+
+	/// Lets systemd know that the watcher isn't stuck, if it's checking.
+	pub fn ping_watchdog(&self) {
+		if self.watchdog.is_some() {
+			self.notify("WATCHDOG=1");
+			self.last_ping.set(Instant::now());
+		}
+	}
+
+	/// When the watchdog should be pinged next, which is halfway through the time systemd
+	/// allows so that a slow check doesn't set it off.
+	pub fn next_ping(&self) -> Option<Instant> {
+		self.watchdog
+			.map(|watchdog| self.last_ping.get() + watchdog / 2)
+	}
+
+	#[cfg(unix)]
+	fn notify(&self, state: &str) {
+		// If systemd can't be reached there's nothing to be done about it
+		let _ = self.socket.send(state.as_bytes());
+	}
+
+	#[cfg(not(unix))]
+	fn notify(&self, _state: &str) {}
+}
+
+/// How often systemd needs to hear from the watcher, if it's watching this process in
+/// particular.
+fn watchdog_interval() -> Option<Duration> {
+	if let Some(pid) = env::var_os("WATCHDOG_PID") {
+		if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+			return None;
+		}
+	}
+	let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+	(usec > 0).then(|| Duration::from_micros(usec))
+}
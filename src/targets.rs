@@ -0,0 +1,299 @@
+//! Working out which files are being watched from the watch patterns.
+
+use crate::{
+	backup,
+	exclude::{self, ExcludePattern},
+	lock,
+	store::STORE_DIR,
+	watcher::WatchConfig,
+};
+use std::{
+	collections::HashMap,
+	env, fs, io,
+	path::{Component, Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// The file of patterns to skip in the top of a directory being watched, like a `.gitignore`.
+pub const IGNORE_FILE: &str = ".watchignore";
+
+/// A `.gitignore`, which is also followed when `use_gitignore` is set.
+const GITIGNORE_FILE: &str = ".gitignore";
+
+/// A single file being watched.
+pub struct WatchTarget {
+	/// The path of the file, as it's referred to in output
+	pub path: String,
+	/// The path to mirror inside the output directory, relative to whatever is being watched
+	pub relative_path: PathBuf,
+	/// The path the file's backups are named after, if it isn't the file's own path (like when
+	/// the file was renamed and it's being followed)
+	pub named_after: Option<String>,
+}
+
+/// Reads a list of watch patterns from a file, one per line.
+///
+/// Blank lines and lines starting with `#` are skipped, and a `~` at the start of a line is the
+/// home directory.
+pub fn read_target_list(path: &Path) -> io::Result<Vec<String>> {
+	let contents = fs::read_to_string(path)?;
+	Ok(contents
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(expand_home)
+		.collect())
+}
+
+fn expand_home(pattern: &str) -> String {
+	let home = env::var("HOME").or_else(|_| env::var("USERPROFILE"));
+	match (pattern.strip_prefix('~'), home) {
+		(Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+			format!("{}{}", home, rest)
+		}
+		_ => pattern.to_owned(),
+	}
+}
+
+/// Expands the watch patterns into the list of files to check.
+///
+/// Patterns that name an existing path (or that contain no glob syntax) are taken literally, which
+/// covers the case where the shell has already expanded a glob into multiple arguments.
+///
+/// Anything that gets in the way of working out the files (like an invalid glob pattern, or a
+/// directory that can't be read) is added to the warnings, for the caller to report.
+pub fn resolve_targets(config: &WatchConfig, warnings: &mut Vec<String>) -> Vec<WatchTarget> {
+	let mut targets = Vec::new();
+	for pattern in &config.watch_patterns {
+		let path = Path::new(pattern);
+		if path.exists() || !is_glob(pattern) {
+			add_target(config, &mut targets, warnings, path);
+			continue;
+		}
+
+		match glob::glob(pattern) {
+			Ok(paths) => {
+				for path in paths.filter_map(Result::ok) {
+					if config.recursive || !path.is_dir() {
+						add_target(config, &mut targets, warnings, &path);
+					}
+				}
+			}
+			Err(e) => warnings.push(format!("Invalid glob pattern {}: {}", pattern, e)),
+		}
+	}
+	disambiguate_targets(&mut targets);
+	targets
+}
+
+/// Makes sure no two targets end up with the same backup names inside the output directory.
+///
+/// Targets that would collide (like two files with the same name in different directories) fall
+/// back to mirroring their full path instead.
+fn disambiguate_targets(targets: &mut [WatchTarget]) {
+	let colliding = targets
+		.iter()
+		.map(|t| {
+			targets
+				.iter()
+				.filter(|o| o.relative_path == t.relative_path)
+				.count() > 1
+		})
+		.collect::<Vec<_>>();
+	for (target, colliding) in targets.iter_mut().zip(colliding) {
+		if colliding {
+			target.relative_path = sanitize_path(Path::new(&target.path));
+		}
+	}
+}
+
+/// Turns a path into a relative one that can safely be placed inside of another directory.
+fn sanitize_path(path: &Path) -> PathBuf {
+	path.components()
+		.filter_map(|component| match component {
+			Component::Prefix(prefix) => Some(
+				prefix
+					.as_os_str()
+					.to_string_lossy()
+					.replace(|c: char| !c.is_alphanumeric(), "")
+					.into(),
+			),
+			Component::RootDir | Component::CurDir => None,
+			Component::ParentDir => Some("_".into()),
+			Component::Normal(name) => Some(name.to_owned()),
+		})
+		.collect()
+}
+
+/// Adds the path to the targets, walking it if it's a directory and watching is recursive.
+fn add_target(
+	config: &WatchConfig,
+	targets: &mut Vec<WatchTarget>,
+	warnings: &mut Vec<String>,
+	path: &Path,
+) {
+	let mut push = |path: &Path, relative_path: PathBuf| {
+		if exclude::is_excluded(&config.excludes, &relative_path, false) || lock::is_lock_file(path)
+		{
+			return;
+		}
+		let path = path.to_string_lossy().into_owned();
+		if !targets.iter().any(|t| t.path == path) {
+			targets.push(WatchTarget {
+				path,
+				relative_path,
+				named_after: None,
+			});
+		}
+	};
+
+	if !path.is_dir() {
+		// Backups are skipped so that broad patterns don't end up backing up backups
+		if !backup::is_backup(&config.name_template, path) {
+			push(
+				path,
+				path.file_name().map_or_else(PathBuf::new, PathBuf::from),
+			);
+		}
+		return;
+	}
+	if !config.recursive {
+		warnings.push(format!(
+			"{} is a directory, use --recursive to watch its contents.",
+			path.display()
+		));
+		return;
+	}
+
+	// Symlinks aren't followed, which also means there's no risk of getting caught in a loop.
+	// Excluded directories (and the hardlink store, which is full of backups) are skipped entirely
+	// rather than walked and filtered.
+	let mut ignore_files = IgnoreFiles::new(config, path);
+	let walker = WalkDir::new(path).into_iter().filter_entry(|entry| {
+		let is_dir = entry.file_type().is_dir();
+		entry.depth() == 0
+			|| !((is_dir && entry.file_name() == STORE_DIR)
+				|| ignore_files.is_ignored(entry.path(), is_dir))
+	});
+	for entry in walker {
+		match entry {
+			Ok(entry) => {
+				if entry.file_type().is_file()
+					&& !backup::is_backup(&config.name_template, entry.path())
+				{
+					let relative_path = entry
+						.path()
+						.strip_prefix(path)
+						.unwrap_or_else(|_| entry.path())
+						.to_path_buf();
+					push(entry.path(), relative_path);
+				}
+			}
+			Err(e) => warnings.push(format!("Skipping {}", e)),
+		}
+	}
+	warnings.append(&mut ignore_files.warnings);
+}
+
+/// The patterns for what to skip in a directory being watched, from the options and the ignore
+/// files in it.
+///
+/// Ignore files are read as they're needed, so that changes to them are picked up the next time
+/// the targets are worked out.
+pub struct IgnoreFiles<'a> {
+	config: &'a WatchConfig,
+	root: &'a Path,
+	/// The `.gitignore` patterns in each directory that's been looked in
+	gitignores: HashMap<PathBuf, Vec<ExcludePattern>>,
+	/// The `.watchignore` patterns in the root
+	watchignore: Vec<ExcludePattern>,
+	/// The problems with reading the ignore files, like invalid patterns in them
+	pub warnings: Vec<String>,
+}
+
+impl<'a> IgnoreFiles<'a> {
+	pub fn new(config: &'a WatchConfig, root: &'a Path) -> Self {
+		let mut warnings = Vec::new();
+		Self {
+			config,
+			root,
+			gitignores: HashMap::new(),
+			watchignore: read_ignore_file(&root.join(IGNORE_FILE), &mut warnings),
+			warnings,
+		}
+	}
+
+	/// Whether the file (or directory) should be skipped.
+	///
+	/// The directories it's in aren't checked, since the walk doesn't go into skipped ones.
+	pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+		let relative_path = match path.strip_prefix(self.root) {
+			Ok(relative_path) => relative_path,
+			Err(_) => return exclude::is_excluded(&self.config.excludes, path, is_dir),
+		};
+
+		// Later patterns take precedence - deeper .gitignores over shallower ones (like with git),
+		// then the .watchignore, then the options
+		let mut ignored = None;
+		if self.config.use_gitignore {
+			let mut dir = self.root.to_path_buf();
+			let mut components = relative_path.components().peekable();
+			loop {
+				let warnings = &mut self.warnings;
+				let patterns = self
+					.gitignores
+					.entry(dir.clone())
+					.or_insert_with(|| read_ignore_file(&dir.join(GITIGNORE_FILE), warnings));
+				if let Some(decision) =
+					exclude::decide(patterns, path.strip_prefix(&dir).unwrap_or(path), is_dir)
+				{
+					ignored = Some(decision);
+				}
+				match components.next() {
+					Some(component) if components.peek().is_some() => dir.push(component),
+					_ => break,
+				}
+			}
+		}
+		for patterns in [&self.watchignore, &self.config.excludes] {
+			if let Some(decision) = exclude::decide(patterns, relative_path, is_dir) {
+				ignored = Some(decision);
+			}
+		}
+		ignored.unwrap_or(false)
+	}
+
+	/// Whether the file (or directory) should be skipped, either by itself or because it's in a
+	/// directory that's skipped.
+	pub fn is_ignored_within(&mut self, path: &Path, is_dir: bool) -> bool {
+		let relative_path = path.strip_prefix(self.root).unwrap_or(path);
+		let mut dir = self.root.to_path_buf();
+		let mut components = relative_path.components().peekable();
+		while let Some(component) = components.next() {
+			if components.peek().is_none() {
+				break;
+			}
+			dir.push(component);
+			if self.is_ignored(&dir, true) {
+				return true;
+			}
+		}
+		self.is_ignored(path, is_dir)
+	}
+}
+
+/// Reads the patterns in an ignore file, if there is one.
+fn read_ignore_file(path: &Path, warnings: &mut Vec<String>) -> Vec<ExcludePattern> {
+	match exclude::read_ignore_file(path, warnings) {
+		Ok(patterns) => patterns,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+		Err(e) => {
+			warnings.push(format!("Unable to read {}: {}", path.display(), e));
+			Vec::new()
+		}
+	}
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+	pattern.contains(['*', '?', '['])
+}
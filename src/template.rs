@@ -0,0 +1,285 @@
+//! Templates describing how backups are named, like `{name}.{timestamp}.bak`.
+
+use crate::timestamp::TimestampFormat;
+use std::path::{Path, PathBuf};
+
+/// The template used when none is given, matching the original naming scheme.
+pub const DEFAULT_TEMPLATE: &str = "{name}.{timestamp}.bak";
+
+/// A single piece of a template.
+#[derive(Clone, PartialEq)]
+enum Segment {
+	Literal(String),
+	/// The full file name of the watched file
+	Name,
+	/// The file name without its extension
+	Stem,
+	/// The extension of the file, without the leading dot
+	Ext,
+	Timestamp,
+	/// The hash of the file's contents, in hex
+	Hash,
+}
+
+/// The parts of a backup's name that vary from backup to backup.
+pub struct Captures {
+	pub timestamp: String,
+	/// The hash, if the template includes it
+	pub hash: Option<String>,
+}
+
+/// A parsed backup name template.
+///
+/// Templates may contain directories (like `backups/{stem}_{timestamp}.{ext}`), which are relative
+/// to the directory backups would otherwise be placed in. Only the file name part may contain the
+/// `{timestamp}` and `{hash}` placeholders, so that every backup of a file ends up in the same
+/// directory.
+#[derive(Clone)]
+pub struct NameTemplate {
+	dirs: Vec<Vec<Segment>>,
+	file_name: Vec<Segment>,
+	timestamp_format: TimestampFormat,
+	/// Whether the `{timestamp}` placeholder holds a sequence number instead of a time
+	numbered: bool,
+}
+
+impl NameTemplate {
+	pub fn parse(template: &str) -> Result<Self, String> {
+		let mut components = template
+			.split(['/', '\\'])
+			.map(parse_component)
+			.collect::<Result<Vec<_>, _>>()?;
+		if components.iter().any(Vec::is_empty) {
+			return Err(String::from(
+				"must be a relative path without any empty components",
+			));
+		}
+
+		let file_name = components.pop().unwrap_or_default();
+		if !file_name.contains(&Segment::Timestamp) {
+			return Err(String::from(
+				"the file name must contain the {timestamp} placeholder",
+			));
+		}
+		if components
+			.iter()
+			.flatten()
+			.any(|s| matches!(s, Segment::Timestamp | Segment::Hash))
+		{
+			return Err(String::from(
+				"{timestamp} and {hash} can only be used in the file name, not in directories",
+			));
+		}
+
+		Ok(Self {
+			dirs: components,
+			file_name,
+			timestamp_format: TimestampFormat::default(),
+			numbered: false,
+		})
+	}
+
+	/// Uses a different format for the `{timestamp}` placeholder than the default.
+	pub fn with_timestamp_format(self, timestamp_format: TimestampFormat) -> Self {
+		Self {
+			timestamp_format,
+			..self
+		}
+	}
+
+	pub fn timestamp_format(&self) -> &TimestampFormat {
+		&self.timestamp_format
+	}
+
+	/// Fills the `{timestamp}` placeholder with sequential numbers (like `0001`) instead of times.
+	pub fn with_numbering(self) -> Self {
+		Self {
+			numbered: true,
+			..self
+		}
+	}
+
+	pub fn is_numbered(&self) -> bool {
+		self.numbered
+	}
+
+	/// The directory backups of the named file are placed in, relative to the backup directory.
+	pub fn dir(&self, name: &str) -> PathBuf {
+		self.dirs
+			.iter()
+			.map(|segments| render(segments, name, "", ""))
+			.collect()
+	}
+
+	/// The path of a backup, relative to the backup directory.
+	pub fn render(&self, name: &str, timestamp: &str, hash: &str) -> PathBuf {
+		self.dir(name)
+			.join(render(&self.file_name, name, timestamp, hash))
+	}
+
+	/// Matches a file name against the template, returning the parts that vary between backups.
+	///
+	/// If no name is given, any file name that could have come from the template matches.
+	pub fn parse_file_name(&self, name: Option<&str>, file_name: &str) -> Option<Captures> {
+		let mut captures = Captures {
+			timestamp: String::new(),
+			hash: None,
+		};
+		let tokens = self
+			.file_name
+			.iter()
+			.map(|segment| match (segment, name) {
+				(Segment::Literal(s), _) => Token::Literal(s.clone()),
+				(Segment::Timestamp, _) if self.numbered => Token::Number,
+				(Segment::Timestamp, _) => Token::Timestamp,
+				(Segment::Hash, _) => Token::Hash,
+				(segment, Some(name)) => {
+					Token::Literal(render(std::slice::from_ref(segment), name, "", ""))
+				}
+				(_, None) => Token::Any,
+			})
+			.collect::<Vec<_>>();
+		if match_tokens(&tokens, file_name, &self.timestamp_format, &mut captures) {
+			Some(captures)
+		} else {
+			None
+		}
+	}
+}
+
+fn parse_component(component: &str) -> Result<Vec<Segment>, String> {
+	let mut segments = Vec::new();
+	let mut rest = component;
+	while !rest.is_empty() {
+		match rest.find('{') {
+			Some(0) => {
+				let end = rest
+					.find('}')
+					.ok_or_else(|| String::from("unterminated placeholder (missing '}')"))?;
+				segments.push(match &rest[1..end] {
+					"name" => Segment::Name,
+					"stem" => Segment::Stem,
+					"ext" => Segment::Ext,
+					"timestamp" => Segment::Timestamp,
+					"hash" => Segment::Hash,
+					other => {
+						return Err(format!(
+							"unknown placeholder {{{}}} (expected one of {{name}}, {{stem}}, \
+							 {{ext}}, {{timestamp}}, or {{hash}})",
+							other
+						))
+					}
+				});
+				rest = &rest[end + 1..];
+			}
+			Some(start) => {
+				push_literal(&mut segments, &rest[..start])?;
+				rest = &rest[start..];
+			}
+			None => {
+				push_literal(&mut segments, rest)?;
+				rest = "";
+			}
+		}
+	}
+	Ok(segments)
+}
+
+fn push_literal(segments: &mut Vec<Segment>, literal: &str) -> Result<(), String> {
+	if literal.contains('}') {
+		return Err(String::from("unmatched '}' outside of a placeholder"));
+	}
+	segments.push(Segment::Literal(literal.to_owned()));
+	Ok(())
+}
+
+fn render(segments: &[Segment], name: &str, timestamp: &str, hash: &str) -> String {
+	let path = Path::new(name);
+	let mut rendered = String::new();
+	for segment in segments {
+		match segment {
+			Segment::Literal(s) => rendered.push_str(s),
+			Segment::Name => rendered.push_str(name),
+			Segment::Stem => {
+				rendered.push_str(
+					&path
+						.file_stem()
+						.map_or_else(|| name.into(), |s| s.to_string_lossy()),
+				);
+			}
+			Segment::Ext => {
+				if let Some(ext) = path.extension() {
+					rendered.push_str(&ext.to_string_lossy());
+				}
+			}
+			Segment::Timestamp => rendered.push_str(timestamp),
+			Segment::Hash => rendered.push_str(hash),
+		}
+	}
+	rendered
+}
+
+/// A piece of a file name to match against.
+enum Token {
+	Literal(String),
+	Timestamp,
+	/// A sequence number standing in for the timestamp
+	Number,
+	Hash,
+	/// Anything at all, used for placeholders when matching backups of any file
+	Any,
+}
+
+fn match_tokens(
+	tokens: &[Token],
+	s: &str,
+	timestamp_format: &TimestampFormat,
+	captures: &mut Captures,
+) -> bool {
+	let (token, rest) = match tokens.split_first() {
+		Some(split) => split,
+		None => return s.is_empty(),
+	};
+	match token {
+		Token::Literal(literal) => s
+			.strip_prefix(literal.as_str())
+			.is_some_and(|s| match_tokens(rest, s, timestamp_format, captures)),
+		// Timestamps can be any length depending on the format, so every possibility is tried
+		Token::Timestamp => (1..=s.len())
+			.rev()
+			.filter(|&len| s.is_char_boundary(len))
+			.any(|len| {
+				let (timestamp, s) = s.split_at(len);
+				if !timestamp_format.matches(timestamp)
+					|| !match_tokens(rest, s, timestamp_format, captures)
+				{
+					return false;
+				}
+				captures.timestamp = timestamp.to_owned();
+				true
+			}),
+		Token::Number => {
+			let digits_len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+			(1..=digits_len).rev().any(|len| {
+				if !match_tokens(rest, &s[len..], timestamp_format, captures) {
+					return false;
+				}
+				captures.timestamp = s[..len].to_owned();
+				true
+			})
+		}
+		Token::Hash => {
+			let hex_len = s.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(s.len());
+			(1..=hex_len).rev().any(|len| {
+				if !match_tokens(rest, &s[len..], timestamp_format, captures) {
+					return false;
+				}
+				captures.hash = Some(s[..len].to_owned());
+				true
+			})
+		}
+		Token::Any => (0..=s.len())
+			.filter(|&len| s.is_char_boundary(len))
+			.any(|len| match_tokens(rest, &s[len..], timestamp_format, captures)),
+	}
+}
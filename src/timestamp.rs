@@ -0,0 +1,84 @@
+//! The format of the timestamps embedded in backup names.
+
+use chrono::{
+	format::{self, Item, Parsed, StrftimeItems},
+	DateTime, Local, NaiveDateTime, TimeZone, Utc,
+};
+
+/// The format used when none is given, which sorts chronologically as a plain string.
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
+
+/// A strftime-style format for timestamps, like `%Y-%m-%d_%H-%M-%S`.
+#[derive(Clone)]
+pub struct TimestampFormat {
+	format: String,
+}
+
+impl TimestampFormat {
+	/// Parses a format, making sure it's valid and that what it produces is safe to put in a file
+	/// name.
+	pub fn parse(format: &str) -> Result<Self, String> {
+		let items = StrftimeItems::new(format).collect::<Vec<_>>();
+		if items.iter().any(|item| matches!(item, Item::Error)) {
+			return Err(String::from(
+				"contains an invalid specifier (see the chrono strftime documentation)",
+			));
+		}
+		if !items
+			.iter()
+			.any(|item| matches!(item, Item::Numeric(..) | Item::Fixed(_)))
+		{
+			return Err(String::from(
+				"must contain at least one specifier, like %Y or %H",
+			));
+		}
+
+		let timestamp_format = Self {
+			format: format.to_owned(),
+		};
+		let rendered = timestamp_format.render(Utc::now().naive_utc());
+		if rendered.contains(['/', '\\']) {
+			return Err(format!(
+				"must not produce path separators (got '{}')",
+				rendered
+			));
+		}
+		Ok(timestamp_format)
+	}
+
+	/// Formats the given time.
+	pub fn render(&self, time: NaiveDateTime) -> String {
+		time.format(&self.format).to_string()
+	}
+
+	/// Whether the string is a timestamp of this format.
+	pub fn matches(&self, timestamp: &str) -> bool {
+		format::parse(
+			&mut Parsed::new(),
+			timestamp,
+			StrftimeItems::new(&self.format),
+		)
+		.is_ok()
+	}
+
+	/// Parses a timestamp in either local time or UTC, if it holds a full date and time.
+	pub fn parse_time(&self, timestamp: &str, local_time: bool) -> Option<DateTime<Utc>> {
+		let time = NaiveDateTime::parse_from_str(timestamp, &self.format).ok()?;
+		if local_time {
+			Local
+				.from_local_datetime(&time)
+				.earliest()
+				.map(|t| t.with_timezone(&Utc))
+		} else {
+			Some(Utc.from_utc_datetime(&time))
+		}
+	}
+}
+
+impl Default for TimestampFormat {
+	fn default() -> Self {
+		Self {
+			format: DEFAULT_TIMESTAMP_FORMAT.to_owned(),
+		}
+	}
+}
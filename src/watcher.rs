@@ -0,0 +1,2881 @@
+//! The watcher itself, which checks the watched files and backs them up when they change.
+
+use crate::{
+	backup::{self, Backup, BackupLocation},
+	codec::{Codec, Encoding},
+	delta,
+	desktop::DesktopNotifier,
+	diff,
+	duration::format_duration,
+	error::WatchError,
+	events,
+	exclude::ExcludePattern,
+	get_timestamp,
+	hash::HashOptions,
+	hash_backup, hash_file, hook,
+	lock::{self, TargetLock},
+	log::{self, LogFile, LogRotation},
+	manifest::{self, ManifestEntry},
+	output::{Color, ColorChoice, Event as OutputEvent, OutputFormat},
+	preserve::Preserve,
+	retention::RetentionPolicy,
+	retry::RetryPolicy,
+	s3::S3Bucket,
+	sftp::{SftpDestination, SftpUploader},
+	size::format_size,
+	state::{FileState, State},
+	store::Store,
+	systemd::Systemd,
+	targets::{self, WatchTarget},
+	template::{NameTemplate, DEFAULT_TEMPLATE},
+	webhook::Webhook,
+};
+use chrono::{Duration, Utc};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use std::{
+	collections::{HashMap, HashSet},
+	fs, io, mem,
+	path::{Path, PathBuf},
+	sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+	thread,
+	time::{self, Instant, SystemTime},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WatchMode {
+	Poll,
+	Events,
+}
+
+/// What to do when a watched file is deleted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnDelete {
+	/// Wait for it to come back, backing it up again if it comes back different
+	Wait,
+	/// Stop with an error
+	Exit,
+	/// Wait for it to come back, and keep its newest backup from being pruned in the meantime
+	BackupLast,
+}
+
+/// What to keep watching when a watched file is renamed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Follow {
+	/// Whatever is at the original path, like a new file made in place of the renamed one
+	Path,
+	/// The renamed file, at its new path
+	Inode,
+}
+
+/// The range a polling interval adapts within, depending on how often changes happen.
+pub struct AdaptiveInterval {
+	/// The shortest the interval gets, which it drops to right after a change
+	pub min: time::Duration,
+	/// The longest the interval gets, after a long time without any changes
+	pub max: time::Duration,
+}
+
+impl AdaptiveInterval {
+	/// The interval to use after a check, which is the shortest one if something changed, and
+	/// otherwise double the current one.
+	pub fn next(&self, interval: time::Duration, changed: bool) -> time::Duration {
+		if changed {
+			self.min
+		} else {
+			(interval * 2).clamp(self.min, self.max)
+		}
+	}
+}
+
+/// Everything about how files are watched and backed up.
+pub struct WatchConfig {
+	/// The files to watch, which may be glob patterns that are re-evaluated on every check
+	pub watch_patterns: Vec<String>,
+	/// Whether to watch every file inside of directories being watched
+	pub recursive: bool,
+	pub excludes: Vec<ExcludePattern>,
+	/// Whether to also skip the files skipped by `.gitignore` files in the directories being
+	/// watched
+	pub use_gitignore: bool,
+	/// Where to place backups, instead of next to the watched files
+	pub output_dir: Option<PathBuf>,
+	pub max_backups: Option<usize>,
+	pub keep_for: Option<Duration>,
+	pub max_total_size: Option<u64>,
+	/// Tiers of backups to keep fewer and fewer of as they get older
+	pub retention: Option<RetentionPolicy>,
+	pub codec: Codec,
+	/// Whether to encrypt backups, with the passphrase set through
+	/// [`crate::encrypt::set_passphrase`]
+	pub encrypt: bool,
+	/// Whether to write backups as binary deltas against the backup before them, and how many
+	/// backups in a row (including the full one they're all based on) make up a chain
+	pub delta: Option<usize>,
+	/// Which metadata of the watched files their backups keep
+	pub preserve: Preserve,
+	/// How file contents are hashed to tell whether they've changed
+	pub hash: HashOptions,
+	/// Whether to skip backing up content that's the same as one of the existing backups
+	pub dedup: bool,
+	/// Whether to make backups with the same content as an earlier one hardlinks to it
+	pub hardlink_dedup: bool,
+	/// Whether to skip hashing files whose size and modification time haven't changed
+	pub fast_path: bool,
+	/// Whether to check that each backup has the same content as the file it's a backup of,
+	/// making it again if not
+	pub verify: bool,
+	/// Whether to only say what backups would be made, without making them or deleting any
+	pub dry_run: bool,
+	/// How to retry reading files that are locked by another program
+	pub lock_retry: RetryPolicy,
+	/// How to retry making backups of files that are locked by another program while they're
+	/// being copied
+	pub copy_retry: RetryPolicy,
+	pub name_template: NameTemplate,
+	/// Where to keep a record of every backup made, which is used to know what's in backups
+	/// without hashing them
+	pub manifest: Option<PathBuf>,
+	/// A shell command to run after each backup
+	pub on_change: Option<String>,
+	/// A shell command to run when an error is reported
+	pub on_error: Option<String>,
+	/// Whether to show desktop notifications for backups and errors
+	pub notify_desktop: bool,
+	/// How long to wait before alerting about the same error again
+	pub on_error_interval: time::Duration,
+	/// Where to send the details of each backup
+	pub webhook: Option<Webhook>,
+	/// Where to upload each backup to, to keep a copy of it somewhere else
+	pub s3: Option<S3Bucket>,
+	/// Whether to remove backups once they've been uploaded
+	pub s3_delete_local: bool,
+	/// Where to send each backup to over SFTP
+	pub sftp: Option<SftpDestination>,
+	/// How long hook commands can run for before they're killed
+	pub hook_timeout: Option<time::Duration>,
+	/// Whether to print what changed in text files when backing them up
+	pub diff: bool,
+	/// Whether the timestamps in backup names are in local time rather than UTC
+	pub local_time: bool,
+	/// A file to remember the last known hashes in between runs
+	pub state_file: Option<PathBuf>,
+	pub watch_mode: WatchMode,
+	/// How often to check for changes when polling
+	pub interval: time::Duration,
+	/// The range to adapt the polling interval within, if it shouldn't stay the same
+	pub adaptive: Option<AdaptiveInterval>,
+	/// How long filesystem activity has to settle down for before checking for changes
+	pub debounce: time::Duration,
+	/// How long to wait after backing up a file before backing up any further changes to it
+	pub cooldown: Option<time::Duration>,
+	/// How long a changed file has to stay the same for before it's backed up
+	pub settle: Option<time::Duration>,
+	/// How long to keep watching for before stopping by itself
+	pub run_for: Option<time::Duration>,
+	/// How many changes to back up before stopping by itself
+	pub max_changes: Option<usize>,
+	/// Whether starting backups count as changes towards `max_changes`
+	pub count_starting_backups: bool,
+	/// How many checks in a row a file can fail to be hashed in before giving up entirely
+	pub missing_grace: Option<usize>,
+	/// How many files to hash at once, defaulting to one per core
+	pub threads: Option<usize>,
+	/// Whether errors should stop the watcher rather than being reported and moved past
+	pub strict: bool,
+	/// Whether to watch files that another watcher is already watching, rather than locking them
+	pub allow_multiple: bool,
+	/// Whether watched files that don't exist when the watcher is created are an error, rather
+	/// than being waited on
+	pub fail_if_missing: bool,
+	pub on_delete: OnDelete,
+	/// What to do when a watched file is renamed, if renames are being looked out for
+	pub follow_rename: Option<Follow>,
+	/// Whether to say which backup has the last content of a watched file when it's deleted
+	pub backup_on_delete: bool,
+	/// Whether to check for changes one last time once told to shut down
+	pub final_backup: bool,
+	/// Whether to watch what symlinks point to, rather than the symlinks themselves
+	pub follow_symlinks: bool,
+	pub quiet: bool,
+	/// A file to add everything that's reported to, even when being quiet
+	pub log_file: Option<PathBuf>,
+	/// When to rotate the log file, if it's rotated by size
+	pub log_rotation: Option<LogRotation>,
+	/// How much more than usual to report, with 0 being the usual amount
+	pub verbosity: u8,
+	pub format: OutputFormat,
+	pub color: ColorChoice,
+}
+
+impl WatchConfig {
+	/// A config for watching the patterns, with everything else left at the defaults.
+	pub fn new(watch_patterns: Vec<String>) -> Self {
+		Self {
+			watch_patterns,
+			recursive: false,
+			excludes: Vec::new(),
+			use_gitignore: false,
+			output_dir: None,
+			max_backups: None,
+			keep_for: None,
+			max_total_size: None,
+			retention: None,
+			codec: Codec::Copy,
+			encrypt: false,
+			delta: None,
+			dedup: false,
+			hardlink_dedup: false,
+			preserve: Preserve::All,
+			hash: HashOptions::default(),
+			fast_path: true,
+			verify: false,
+			dry_run: false,
+			lock_retry: RetryPolicy::default(),
+			copy_retry: RetryPolicy::default(),
+			name_template: NameTemplate::parse(DEFAULT_TEMPLATE).unwrap(),
+			manifest: None,
+			on_change: None,
+			on_error: None,
+			on_error_interval: time::Duration::from_secs(10 * 60),
+			hook_timeout: None,
+			webhook: None,
+			s3: None,
+			s3_delete_local: false,
+			sftp: None,
+			notify_desktop: false,
+			diff: false,
+			local_time: false,
+			state_file: None,
+			watch_mode: WatchMode::Events,
+			interval: time::Duration::from_millis(5000),
+			adaptive: None,
+			debounce: time::Duration::from_millis(200),
+			cooldown: None,
+			settle: None,
+			run_for: None,
+			max_changes: None,
+			count_starting_backups: false,
+			missing_grace: None,
+			threads: None,
+			strict: false,
+			allow_multiple: false,
+			fail_if_missing: false,
+			on_delete: OnDelete::Wait,
+			backup_on_delete: false,
+			follow_rename: None,
+			final_backup: false,
+			follow_symlinks: true,
+			quiet: false,
+			log_file: None,
+			log_rotation: None,
+			verbosity: 0,
+			format: OutputFormat::Text,
+			color: ColorChoice::Auto,
+		}
+	}
+
+	/// How backups get written, going by the codec and whether to encrypt them.
+	pub fn encoding(&self) -> Encoding {
+		Encoding {
+			codec: self.codec,
+			encrypted: self.encrypt,
+		}
+	}
+}
+
+/// Instructions for a running watcher.
+pub enum Control {
+	/// Stop watching and return from [`Watcher::run`]
+	Shutdown,
+	/// Back up every target right away, even the ones that haven't changed
+	Backup,
+	/// Switch to a new config, keeping everything that's known about the targets
+	Reload(Box<WatchConfig>),
+	/// Report how each target is doing
+	Status,
+	/// Stop checking for changes until told to resume
+	Pause,
+	/// Start checking for changes again, checking right away for any made while paused
+	Resume,
+}
+
+/// Why a watcher stopped running.
+pub enum StopReason {
+	/// It was told to through a [`Controller`]
+	Shutdown,
+	/// It ran for the `run_for` duration
+	RunFor,
+	/// It backed up `max_changes` changes
+	MaxChanges,
+}
+
+impl StopReason {
+	/// A short name for the reason, like `run_for`.
+	pub fn name(&self) -> &'static str {
+		match self {
+			StopReason::Shutdown => "shutdown",
+			StopReason::RunFor => "run_for",
+			StopReason::MaxChanges => "max_changes",
+		}
+	}
+}
+
+/// Anything that can wake up a running watcher.
+enum Message {
+	Control(Control),
+	Event(notify::Result<Event>),
+}
+
+/// A handle for sending instructions to a watcher while it's running.
+#[derive(Clone)]
+pub struct Controller(Sender<Message>);
+
+impl Controller {
+	pub fn send(&self, control: Control) {
+		// If the watcher is gone there's nobody left to instruct, which is fine
+		let _ = self.0.send(Message::Control(control));
+	}
+}
+
+/// A backup that was made.
+pub struct BackupResult {
+	/// The file that was backed up
+	pub watch_file: String,
+	/// Where the backup was written to
+	pub backup_file: PathBuf,
+	pub timestamp: String,
+	/// The hash of the backed up content, in hex
+	pub hash: String,
+	/// Whether this was the first backup made of the file, rather than one made after a change
+	pub initial: bool,
+	/// Whether this backup was asked for, rather than being made because of a change
+	pub manual: bool,
+}
+
+/// A target that was restored from one of its backups.
+pub struct RestoreResult {
+	/// The file that was restored
+	pub watch_file: String,
+	/// The backup it was restored from
+	pub backup_file: PathBuf,
+	/// Where the content it had before being restored was backed up to, if it needed to be
+	pub safety_backup: Option<PathBuf>,
+	/// Whether the file was actually written to, rather than already matching the backup (or
+	/// this being a dry run)
+	pub restored: bool,
+}
+
+/// The cheap-to-check metadata of a file, used to tell whether it could have changed.
+#[derive(PartialEq)]
+struct FileStats {
+	len: u64,
+	modified: Option<SystemTime>,
+	/// Where the file points to, if it's a symlink that isn't being followed
+	link: Option<PathBuf>,
+	/// Which file it is, so that a file replaced by another one with the same size and
+	/// modification time (like by saving to a temporary file and renaming it over the old one)
+	/// is still hashed
+	identity: Option<FileIdentity>,
+}
+
+/// What tells files apart, even when one has replaced another at the same path.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileIdentity {
+	device: u64,
+	inode: u64,
+}
+
+/// A target that was hashed ahead of time, along with the other targets.
+struct Prehashed {
+	/// The target's stats from just before it was hashed, when using the fast path
+	stats: Option<FileStats>,
+	hash: Result<String, WatchError>,
+	took: time::Duration,
+}
+
+/// What the watcher has done so far, for a summary at the end.
+#[derive(Default)]
+struct Session {
+	/// How many times the targets have been checked
+	checks: usize,
+	/// How many backups have been made, including starting backups
+	backups: usize,
+	/// The total size of the backups that have been made
+	bytes_written: u64,
+	newest_backup: Option<PathBuf>,
+}
+
+/// When a target was last backed up, and what its content was.
+struct LastBackup {
+	time: Instant,
+	hash: String,
+}
+
+pub struct Watcher {
+	config: WatchConfig,
+	cached_hashes: HashMap<String, String>,
+	state: State,
+	/// The number of the latest backup of each target, when numbering backups
+	numbers: HashMap<String, u64>,
+	/// How many checks in a row each target has failed to be hashed in
+	failed_checks: HashMap<String, usize>,
+	/// The latest backup of each target made by the watcher
+	last_backups: HashMap<String, LastBackup>,
+	/// The hashes of the existing backups of each target, when deduplicating
+	backup_hashes: HashMap<String, HashMap<String, PathBuf>>,
+	/// When the user was last alerted about each error message
+	error_alerts: HashMap<String, Instant>,
+	/// Desktop notifications, if they're wanted
+	desktop: Option<DesktopNotifier>,
+	sftp: Option<SftpUploader>,
+	/// The connection to systemd while running, if the watcher is running under it
+	systemd: Option<Systemd>,
+	/// What's watching for filesystem events while running, if that's how changes are noticed
+	event_watcher: Option<RecommendedWatcher>,
+	log_file: Option<LogFile>,
+	/// Whether the last attempt at writing to the log file failed
+	log_failing: bool,
+	/// The size and modification time of each target when it was last hashed, along with the hash
+	seen: HashMap<String, (FileStats, String)>,
+	/// Which file each target was the last time it was checked, to tell when it's been replaced
+	identities: HashMap<String, FileIdentity>,
+	/// Where each target that was renamed is now, by the path it had, when following renamed files
+	followed: HashMap<String, String>,
+	/// The threads targets are hashed on, once there's more than one target to hash at a time
+	pool: Option<ThreadPool>,
+	/// The targets hashed ahead of time for the check that's underway
+	prehashed: HashMap<String, Prehashed>,
+	/// The locks on the targets, so that other watchers don't watch them too
+	locks: HashMap<String, TargetLock>,
+	/// The targets that are locked by another watcher
+	locked_out: HashSet<String>,
+	/// The targets that can't be locked at all, like ones in read-only directories
+	unlockable: HashSet<String>,
+	/// The targets that didn't exist the last time they were checked, and haven't existed before
+	waiting: HashSet<String>,
+	/// The targets that existed before, but have been missing for a while now
+	deleted: HashSet<String>,
+	/// The files that filesystem events have said were removed since the last check
+	removed: HashSet<PathBuf>,
+	/// The problems with working out the targets that have already been reported
+	target_warnings: HashSet<String>,
+	/// How many errors have been reported (rather than returned) so far
+	error_count: usize,
+	/// How many backups have been made of changes (not counting starting backups) so far
+	change_count: usize,
+	session: Session,
+	/// Whether the watcher is making its final check before stopping, which isn't held off by
+	/// cooldowns
+	finishing: bool,
+	/// Whether the watcher is backing up every target whether they've changed or not
+	forcing: bool,
+	/// Whether checking for changes is paused
+	paused: bool,
+	/// When the watcher was created, which `run_for` counts from
+	started: Instant,
+	sender: Sender<Message>,
+	receiver: Receiver<Message>,
+}
+
+impl Watcher {
+	/// Creates a watcher, picking up where the last run left off if there's a state file.
+	pub fn new(config: WatchConfig) -> Result<Self, WatchError> {
+		let mut cached_hashes = HashMap::new();
+		let state = match &config.state_file {
+			Some(state_file) => State::load(state_file).map_err(WatchError::LoadState)?,
+			None => State::default(),
+		};
+		for (watch_file, file_state) in &state.files {
+			cached_hashes.insert(watch_file.clone(), file_state.hash.clone());
+		}
+
+		if config.fail_if_missing {
+			if let Some(missing) = targets::resolve_targets(&config, &mut Vec::new())
+				.into_iter()
+				.find(|target| !Path::new(&target.path).exists())
+			{
+				return Err(WatchError::Missing { path: missing.path });
+			}
+		}
+		let desktop = config.notify_desktop.then(DesktopNotifier::new);
+		let sftp = config.sftp.clone().map(SftpUploader::new);
+		let log_file = match &config.log_file {
+			Some(path) => {
+				let mut log_file = LogFile::open(path).map_err(WatchError::OpenLog)?;
+				log_file.set_rotation(config.log_rotation);
+				Some(log_file)
+			}
+			None => None,
+		};
+		let (sender, receiver) = mpsc::channel();
+		Ok(Self {
+			config,
+			cached_hashes,
+			state,
+			numbers: HashMap::new(),
+			failed_checks: HashMap::new(),
+			last_backups: HashMap::new(),
+			error_alerts: HashMap::new(),
+			desktop,
+			sftp,
+			systemd: None,
+			event_watcher: None,
+			log_file,
+			log_failing: false,
+			backup_hashes: HashMap::new(),
+			seen: HashMap::new(),
+			identities: HashMap::new(),
+			followed: HashMap::new(),
+			pool: None,
+			prehashed: HashMap::new(),
+			locks: HashMap::new(),
+			locked_out: HashSet::new(),
+			unlockable: HashSet::new(),
+			waiting: HashSet::new(),
+			deleted: HashSet::new(),
+			removed: HashSet::new(),
+			target_warnings: HashSet::new(),
+			error_count: 0,
+			change_count: 0,
+			session: Session::default(),
+			finishing: false,
+			forcing: false,
+			paused: false,
+			started: Instant::now(),
+			sender,
+			receiver,
+		})
+	}
+
+	pub fn config(&self) -> &WatchConfig {
+		&self.config
+	}
+
+	/// How many errors have been reported and moved past so far.
+	pub fn error_count(&self) -> usize {
+		self.error_count
+	}
+
+	/// How many backups have been made of changes so far, not counting starting backups.
+	pub fn change_count(&self) -> usize {
+		self.change_count
+	}
+
+	/// A handle for instructing the watcher while it runs, like telling it to shut down.
+	pub fn controller(&self) -> Controller {
+		Controller(self.sender.clone())
+	}
+
+	/// Expands the watch patterns into the list of files to check.
+	///
+	/// Anything that got in the way of that is reported the first time it happens, rather than with
+	/// every check.
+	pub fn resolve_targets(&mut self) -> Vec<WatchTarget> {
+		let mut warnings = Vec::new();
+		let mut targets = targets::resolve_targets(&self.config, &mut warnings);
+		for warning in warnings {
+			if !self.target_warnings.contains(&warning) {
+				self.warn(&warning);
+				self.target_warnings.insert(warning);
+			}
+		}
+		for target in &mut targets {
+			if let Some(path) = self.followed.get(&target.path) {
+				target.named_after = Some(mem::replace(&mut target.path, path.clone()));
+			}
+		}
+		targets
+	}
+
+	/// Remembers the current hashes of any targets that don't have one yet, without backing them
+	/// up.
+	///
+	/// This way only changes made from now on are backed up.
+	pub fn cache_hashes(&mut self) -> Result<(), WatchError> {
+		let timestamp = get_timestamp(
+			self.config.name_template.timestamp_format(),
+			self.config.local_time,
+		);
+		for target in self.resolve_targets() {
+			if self.cached_hashes.contains_key(&target.path) {
+				continue;
+			}
+			if !Path::new(&target.path).exists() {
+				if self.waiting.insert(target.path.clone()) {
+					self.say_in(
+						Color::Yellow,
+						&format!("Waiting for {} to be created.", target.path),
+					);
+				}
+				continue;
+			}
+			if let Ok(hash) = hash_file(Path::new(&target.path), &self.config.hash) {
+				self.record_hash(&target.path, &hash, &timestamp)?;
+				// Otherwise a rename before the first change wouldn't be noticed
+				self.notice_replaced(&target.path);
+			}
+		}
+		Ok(())
+	}
+
+	/// Remembers the hashes of the newest existing backups of any targets that don't have a hash
+	/// yet, so that they're only backed up again if they've changed since.
+	///
+	/// Targets without any backups are left alone, so they get a starting backup. When backups are
+	/// removed once they're uploaded, the newest one in the manifest is used instead of an existing
+	/// backup.
+	pub fn cache_hashes_from_backups(&mut self) -> Result<(), WatchError> {
+		for target in self.resolve_targets() {
+			if self.cached_hashes.contains_key(&target.path) {
+				continue;
+			}
+			let newest = match self.backups(&target) {
+				Ok(backups) => backups.into_iter().last(),
+				Err(e) => {
+					self.report_error(e)?;
+					continue;
+				}
+			};
+			if newest.is_none() && self.config.s3_delete_local {
+				if let Some(hash) = self.uploaded_hash(&target) {
+					self.notice_replaced(&target.path);
+					self.cached_hashes.insert(target.path, hash);
+					continue;
+				}
+			}
+			if let Some(newest) = newest {
+				match self.backup_hash(&newest) {
+					Ok(hash) => {
+						self.notice_replaced(&target.path);
+						self.cached_hashes.insert(target.path, hash);
+					}
+					Err(source) => self.report_error(WatchError::ReadBackups {
+						path: target.path.clone(),
+						source,
+					})?,
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Finds the existing backups of a target, oldest first.
+	///
+	/// With a manifest, the hashes of the backups recorded in it are filled in. A manifest that
+	/// can't be read is ignored, since the backups can always be hashed instead.
+	pub fn backups(&self, target: &WatchTarget) -> Result<Vec<Backup>, WatchError> {
+		let mut backups = self
+			.backup_location(target)
+			.find_backups()
+			.map_err(|source| WatchError::ReadBackups {
+				path: target.path.clone(),
+				source,
+			})?;
+		let entries = self
+			.config
+			.manifest
+			.as_ref()
+			.and_then(|manifest| manifest::load(manifest).ok())
+			.unwrap_or_default();
+		let algorithm = self.config.hash.algorithm.name();
+		for entry in entries {
+			if entry.file != target.path || entry.algorithm != algorithm {
+				continue;
+			}
+			if let Some(backup) = backups
+				.iter_mut()
+				.find(|backup| backup.hash.is_none() && backup.path == entry.backup)
+			{
+				backup.hash = Some(entry.hash);
+			}
+		}
+		Ok(backups)
+	}
+
+	/// The hash of the newest backup of the target in the manifest, for when it's no longer kept
+	/// locally.
+	fn uploaded_hash(&self, target: &WatchTarget) -> Option<String> {
+		let entries = manifest::load(self.config.manifest.as_ref()?).ok()?;
+		let algorithm = self.config.hash.algorithm.name();
+		entries
+			.into_iter()
+			.rev()
+			.find(|entry| entry.file == target.path && entry.algorithm == algorithm)
+			.map(|entry| entry.hash)
+	}
+
+	/// The hash of a backup's original contents, which is only worked out if it isn't known yet.
+	fn backup_hash(&self, backup: &Backup) -> io::Result<String> {
+		match &backup.hash {
+			Some(hash) => Ok(hash.clone()),
+			None => hash_backup(&backup.path, self.config.hash.algorithm),
+		}
+	}
+
+	/// Restores a target from its newest backup, or from the one made at the given timestamp.
+	///
+	/// Unless its current content is already backed up as its newest backup, the target is backed
+	/// up first so that restoring it never loses anything. With `dry_run`, nothing is written and
+	/// what would happen is only printed.
+	pub fn restore(
+		&mut self,
+		target: &WatchTarget,
+		timestamp: Option<&str>,
+		dry_run: bool,
+	) -> Result<RestoreResult, WatchError> {
+		let watch_file = target.path.as_str();
+		let location = self.backup_location(target);
+		let read_backups_error = |source| WatchError::ReadBackups {
+			path: watch_file.to_owned(),
+			source,
+		};
+
+		let mut backups = self.backups(target)?;
+		let index = match timestamp {
+			Some(timestamp) => backups.iter().rposition(|b| b.timestamp == timestamp),
+			None => backups.len().checked_sub(1),
+		}
+		.ok_or_else(|| WatchError::NoBackup {
+			path: watch_file.to_owned(),
+			timestamp: timestamp.map(String::from),
+		})?;
+		let newest = backups.pop();
+		let backup = if index == backups.len() {
+			newest.as_ref().unwrap()
+		} else {
+			&backups[index]
+		};
+		let backup_hash = self.backup_hash(backup).map_err(read_backups_error)?;
+
+		// A file that doesn't exist anymore has nothing that needs backing up
+		let current_hash = match self.hash_target(watch_file) {
+			Ok(hash) => Some(hash),
+			Err(e) if e.is_missing_file() => None,
+			Err(e) => return Err(e),
+		};
+		let mut result = RestoreResult {
+			watch_file: watch_file.to_owned(),
+			backup_file: backup.path.clone(),
+			safety_backup: None,
+			restored: false,
+		};
+		if current_hash.as_ref() == Some(&backup_hash) {
+			self.say(&format!(
+				"{} already matches {}, leaving it as it is.",
+				watch_file,
+				backup.path.display()
+			));
+			return Ok(result);
+		}
+
+		// The current content only needs backing up if it isn't the newest backup already (it can't
+		// be the one being restored, since that was just checked)
+		let needs_safety_backup = match (&current_hash, &newest) {
+			(Some(current_hash), Some(newest)) if newest.path != backup.path => {
+				&self.backup_hash(newest).map_err(read_backups_error)? != current_hash
+			}
+			(current_hash, _) => current_hash.is_some(),
+		};
+		if dry_run {
+			if needs_safety_backup {
+				self.say(&format!(
+					"Would back up the current content of {}.",
+					watch_file
+				));
+			}
+			self.say(&format!(
+				"Would restore {} from {}.",
+				watch_file,
+				backup.path.display()
+			));
+			return Ok(result);
+		}
+
+		let timestamp = get_timestamp(
+			self.config.name_template.timestamp_format(),
+			self.config.local_time,
+		);
+		if let (true, Some(current_hash)) = (needs_safety_backup, &current_hash) {
+			let safety_backup = self.write_backup(target, &location, current_hash, &timestamp)?;
+			self.say(&format!(
+				"Backed up the current content of {} to {}.",
+				watch_file,
+				safety_backup.display()
+			));
+			result.safety_backup = Some(safety_backup);
+		}
+
+		self.config
+			.lock_retry
+			.run(|| {
+				backup::restore_backup(&backup.path, Path::new(watch_file), self.config.preserve)
+			})
+			.map_err(|source| WatchError::Restore {
+				path: watch_file.to_owned(),
+				source,
+			})?;
+		self.record_hash(watch_file, &backup_hash, &timestamp)?;
+		result.restored = true;
+		self.say(&format!(
+			"Restored {} from {}.",
+			watch_file,
+			backup.path.display()
+		));
+		Ok(result)
+	}
+
+	/// Deletes any temporary files left behind by backups of the targets that were interrupted, like
+	/// by the program being killed partway through one.
+	pub fn remove_temp_files(&mut self) -> Result<(), WatchError> {
+		if self.config.dry_run {
+			return Ok(());
+		}
+		for target in self.resolve_targets() {
+			match self.backup_location(&target).remove_temp_files() {
+				Ok(removed) => {
+					for path in removed {
+						self.say(&format!(
+							"Removed {}, left over from an interrupted backup.",
+							path.display()
+						));
+					}
+				}
+				Err(source) => self.report_error(WatchError::Prune {
+					path: target.path.clone(),
+					source,
+				})?,
+			}
+		}
+		Ok(())
+	}
+
+	/// Checks every target once, backing up the ones that have changed.
+	///
+	/// Errors with individual targets are reported and the rest are still checked, unless the
+	/// watcher is strict, in which case the first error is returned.
+	pub fn check_once(&mut self) -> Result<Vec<BackupResult>, WatchError> {
+		self.session.checks += 1;
+		if let Some(systemd) = &self.systemd {
+			systemd.ping_watchdog();
+		}
+		let targets = self.resolve_targets();
+
+		// Forget about files that no longer match any of the patterns
+		self.cached_hashes
+			.retain(|watch_file, _| targets.iter().any(|t| &t.path == watch_file));
+		self.failed_checks
+			.retain(|watch_file, _| targets.iter().any(|t| &t.path == watch_file));
+		self.seen
+			.retain(|watch_file, _| targets.iter().any(|t| &t.path == watch_file));
+		self.backup_hashes
+			.retain(|watch_file, _| targets.iter().any(|t| &t.path == watch_file));
+		self.waiting
+			.retain(|watch_file| targets.iter().any(|t| &t.path == watch_file));
+		self.deleted
+			.retain(|watch_file| targets.iter().any(|t| &t.path == watch_file));
+		self.locks
+			.retain(|watch_file, _| targets.iter().any(|t| &t.path == watch_file));
+		self.locked_out
+			.retain(|watch_file| targets.iter().any(|t| &t.path == watch_file));
+
+		let mut targets = targets;
+		let mut locked = Vec::new();
+		targets.retain(|target| match self.lock_target(&target.path) {
+			Ok(()) => true,
+			Err(e) => {
+				locked.push(e);
+				false
+			}
+		});
+		for e in locked {
+			// Being locked out is only reported once, rather than on every check
+			if let WatchError::Locked { path, .. } = &e {
+				if !self.locked_out.insert(path.clone()) {
+					continue;
+				}
+			}
+			self.report_error(e)?;
+		}
+
+		self.hash_ahead(&targets);
+		let mut results = Vec::new();
+		for target in &targets {
+			match self.check_target(target) {
+				Ok(result) => {
+					self.failed_checks.remove(&target.path);
+					if let Some(result) = result {
+						if !result.manual && (!result.initial || self.config.count_starting_backups)
+						{
+							self.change_count += 1;
+						}
+						results.push(result);
+					}
+				}
+				Err(e) => {
+					// Files commonly vanish for a moment while they're replaced on save, so they're
+					// given a few chances before giving up on them
+					if let WatchError::Hash { path, .. } = &e {
+						let checks = self.failed_checks.entry(path.clone()).or_insert(0);
+						*checks += 1;
+						let checks = *checks;
+
+						// Once a file has been missing for more than a single check (or there was
+						// an event saying it was removed), it's treated as deleted rather than as
+						// being in the middle of being saved. That's handled before giving up on
+						// it, so that what happened to it (and where its last content is) is
+						// always reported.
+						let removed = events::absolute_path(Path::new(path))
+							.is_some_and(|path| self.removed.contains(&path));
+						let deleted = e.is_missing_file()
+							&& (checks >= 2 || removed)
+							&& self.cached_hashes.contains_key(path);
+						let reported = deleted && self.notice_deleted(target)?;
+						if self
+							.config
+							.missing_grace
+							.is_some_and(|grace| checks > grace)
+						{
+							return Err(WatchError::GaveUp {
+								path: path.clone(),
+								checks,
+							});
+						}
+						if deleted && !reported {
+							self.prune_expired(target)?;
+							continue;
+						}
+					}
+					self.report_error(e)?;
+				}
+			}
+			self.prune_expired(target)?;
+		}
+		self.prehashed.clear();
+		self.removed.clear();
+		Ok(results)
+	}
+
+	/// Backs up every target right away, whether it's changed or not.
+	///
+	/// Backups made like this are pruned like any other, but don't count towards `max_changes`.
+	pub fn backup_now(&mut self) -> Result<Vec<BackupResult>, WatchError> {
+		self.forcing = true;
+		let results = self.check_once();
+		self.forcing = false;
+		results
+	}
+
+	/// Locks every target, so that other watchers can't watch them too.
+	///
+	/// This doesn't need to be called before checking the targets (which lock them as they come
+	/// up), but catches a target that's already being watched before anything else happens.
+	pub fn lock_targets(&mut self) -> Result<(), WatchError> {
+		for target in self.resolve_targets() {
+			self.lock_target(&target.path)?;
+		}
+		Ok(())
+	}
+
+	/// Carries on in a process forked from the one the watcher was made in.
+	///
+	/// Only the thread that forked carries on in the new process, so the thread pool is let go of
+	/// (without waiting on threads that aren't there) and made again the next time it's needed.
+	/// The lock files are updated with the new process's ID too.
+	pub fn detached(&mut self) {
+		if let Some(pool) = self.pool.take() {
+			mem::forget(pool);
+		}
+		let mut failed = Vec::new();
+		for (watch_file, lock) in &mut self.locks {
+			if let Err(e) = lock.write_pid() {
+				failed.push(format!(
+					"Unable to update the lock file of {} with the new process ID: {}",
+					watch_file, e
+				));
+			}
+		}
+		for message in failed {
+			self.warn(&message);
+		}
+	}
+
+	/// Locks the target, unless it's already locked or locking isn't wanted.
+	///
+	/// Targets that can't be locked (like ones in read-only directories) are watched anyway,
+	/// with a warning.
+	fn lock_target(&mut self, watch_file: &str) -> Result<(), WatchError> {
+		if self.config.allow_multiple
+			|| self.locks.contains_key(watch_file)
+			|| self.unlockable.contains(watch_file)
+		{
+			return Ok(());
+		}
+		match TargetLock::acquire(Path::new(watch_file), self.config.output_dir.as_deref()) {
+			Ok(Ok(lock)) => {
+				self.locks.insert(watch_file.to_owned(), lock);
+				self.locked_out.remove(watch_file);
+				Ok(())
+			}
+			Ok(Err(pid)) => Err(WatchError::Locked {
+				path: watch_file.to_owned(),
+				pid,
+			}),
+			// The target's directory may just not exist yet
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(e) => {
+				self.warn(&format!(
+					"Unable to lock {} ({}), so another watcher could watch it at the same time.",
+					watch_file, e
+				));
+				self.unlockable.insert(watch_file.to_owned());
+				Ok(())
+			}
+		}
+	}
+
+	/// Hashes the targets that need to be hashed all at once on the thread pool, so that checking
+	/// them one by one afterwards doesn't have to wait on each of them in turn.
+	///
+	/// Nothing is hashed ahead of time when there's only one thread, or only one target to hash.
+	fn hash_ahead(&mut self, targets: &[WatchTarget]) {
+		self.prehashed.clear();
+		if self.config.threads == Some(1) {
+			return;
+		}
+		let to_hash: Vec<(&str, Option<FileStats>)> = targets
+			.iter()
+			.map(|target| {
+				let stats = self
+					.config
+					.fast_path
+					.then(|| file_stats(&self.config, &target.path))
+					.flatten();
+				(target.path.as_str(), stats)
+			})
+			.filter(|(watch_file, stats)| {
+				!self.config.fast_path || self.seen_hash(watch_file, stats.as_ref()).is_none()
+			})
+			.collect();
+		if to_hash.len() < 2 {
+			return;
+		}
+
+		// A pool that can't be made just means hashing one target at a time
+		if self.pool.is_none() {
+			self.pool = ThreadPoolBuilder::new()
+				.num_threads(self.config.threads.unwrap_or(0))
+				.build()
+				.ok();
+		}
+		let (pool, config) = match &self.pool {
+			Some(pool) => (pool, &self.config),
+			None => return,
+		};
+		let prehashed: Vec<(String, Prehashed)> = pool.install(|| {
+			to_hash
+				.into_par_iter()
+				.map(|(watch_file, stats)| {
+					let started = Instant::now();
+					let hash = hash_contents(config, watch_file);
+					let prehashed = Prehashed {
+						stats,
+						hash,
+						took: started.elapsed(),
+					};
+					(watch_file.to_owned(), prehashed)
+				})
+				.collect()
+		});
+		self.prehashed.extend(prehashed);
+	}
+
+	/// Checks a single target for changes, backing it up if it has changed.
+	///
+	/// Errors that stop the backup from being made are returned, while ones that come up
+	/// afterwards (like failing to prune old backups) are reported without affecting the backup.
+	pub fn check_target(
+		&mut self,
+		target: &WatchTarget,
+	) -> Result<Option<BackupResult>, WatchError> {
+		let watch_file = target.path.as_str();
+		if self.config.follow_rename.is_some() {
+			if let Some(followed) = self.notice_renamed(target) {
+				return self.check_target(&followed);
+			}
+		}
+
+		// Files that haven't been created yet are waited on, rather than them being an error
+		if !self.cached_hashes.contains_key(watch_file) && !Path::new(watch_file).exists() {
+			if self.waiting.insert(watch_file.to_owned()) {
+				self.say_in(
+					Color::Yellow,
+					&format!("Waiting for {} to be created.", watch_file),
+				);
+			}
+			return Ok(None);
+		}
+		self.waiting.remove(watch_file);
+		if self.deleted.contains(watch_file) && Path::new(watch_file).exists() {
+			self.deleted.remove(watch_file);
+			self.say(&format!("{} is back.", watch_file));
+		}
+		self.notice_replaced(watch_file);
+
+		// Calculate hash - a file that can't be read is skipped so the others keep being watched
+		let mut hash = self.hash_target_cheaply(watch_file)?;
+
+		// Check if the file has changed, and if it has, a backup should be made
+		let cached_hash = self.cached_hashes.get(watch_file).cloned();
+		if !self.forcing && self.is_up_to_date(watch_file, &hash) {
+			self.detail(1, &format!("No change to {} ({}).", watch_file, hash));
+			self.print_event(OutputEvent::NoChange {
+				file: watch_file,
+				hash: &hash,
+			});
+			return Ok(None);
+		}
+
+		// Hold off on backing up changes made soon after the last backup, just keeping track of
+		// them so that the latest content gets backed up once the cooldown is over
+		if let (Some(cooldown), Some(last_backup), false) = (
+			self.config.cooldown,
+			self.last_backups.get(watch_file),
+			self.finishing || self.forcing,
+		) {
+			if last_backup.time.elapsed() < cooldown {
+				if cached_hash.as_ref() != Some(&hash) {
+					self.say_in(
+						Color::Yellow,
+						&format!(
+							"{} changed, but it was backed up less than {}ms ago - holding off \
+							 until the cooldown is over.",
+							watch_file,
+							cooldown.as_millis()
+						),
+					);
+					// Only the in-memory hash is updated, so that the state file still reflects
+					// what was actually backed up
+					self.cached_hashes.insert(watch_file.to_owned(), hash);
+				}
+				return Ok(None);
+			}
+		}
+
+		// Make sure the file is done being written before backing it up
+		if let Some(settle) = self.config.settle {
+			loop {
+				thread::sleep(settle);
+				let settled_hash = self.hash_target(watch_file)?;
+				if settled_hash == hash {
+					break;
+				}
+				self.say_in(
+					Color::Yellow,
+					&format!(
+						"{} is still changing, waiting for it to settle before backing it up.",
+						watch_file
+					),
+				);
+				hash = settled_hash;
+			}
+			if !self.forcing && self.is_up_to_date(watch_file, &hash) {
+				return Ok(None);
+			}
+		}
+		let timestamp = get_timestamp(
+			self.config.name_template.timestamp_format(),
+			self.config.local_time,
+		);
+
+		// Content that's already backed up doesn't need to be again, like when a change is undone
+		if self.config.dedup && !self.forcing {
+			if let Some(existing) = self.existing_backup(target, &hash) {
+				self.say(&format!(
+					"{} changed, but its content is already backed up as {} - not backing it up \
+					 again.",
+					watch_file,
+					existing.display()
+				));
+				self.print_event(OutputEvent::Duplicate {
+					timestamp: &timestamp,
+					file: watch_file,
+					backup: &existing,
+					hash: &hash,
+				});
+				self.record_hash(watch_file, &hash, &timestamp)?;
+				return Ok(None);
+			}
+		}
+
+		if self.forcing {
+			self.say_in(
+				Color::Green,
+				&format!("Manual backup of {}. {}: {}", watch_file, timestamp, hash),
+			);
+		} else if cached_hash.is_none() {
+			self.say_in(
+				Color::Green,
+				&format!(
+					"Making a starting backup of {}. {}: {}",
+					watch_file, timestamp, hash
+				),
+			);
+		} else {
+			self.say_in(
+				Color::Green,
+				&format!("{} changed! {}: {}", watch_file, timestamp, hash),
+			);
+		}
+
+		let location = self.backup_location(target);
+		if self.config.dry_run {
+			return self.pretend_backup(target, &location, &hash, &timestamp);
+		}
+		let previous_backup = if self.config.diff && self.says_text() {
+			match location.find_backups() {
+				Ok(backups) => backups.into_iter().last(),
+				Err(source) => {
+					self.report_error(WatchError::ReadBackups {
+						path: watch_file.to_owned(),
+						source,
+					})?;
+					None
+				}
+			}
+		} else {
+			None
+		};
+		let mut backup_file = self.write_backup(target, &location, &hash, &timestamp)?;
+		let size = fs::metadata(watch_file).ok().map(|metadata| metadata.len());
+		self.print_event(OutputEvent::BackupCreated {
+			timestamp: &timestamp,
+			file: watch_file,
+			backup: &backup_file,
+			hash: &hash,
+			size,
+			starting: cached_hash.is_none(),
+			manual: self.forcing,
+		});
+		if let Some(backup_hashes) = self.backup_hashes.get_mut(watch_file) {
+			backup_hashes.insert(hash.clone(), backup_file.clone());
+		}
+		if let Some(previous_backup) = previous_backup {
+			match diff::diff_backups(&previous_backup.path, &backup_file) {
+				Ok(Some(diff)) => self.say(diff.trim_end_matches('\n')),
+				Ok(None) => self.say(&format!(
+					"{} is a binary file, so there's no diff to show.",
+					watch_file
+				)),
+				Err(source) => self.report_error(WatchError::ReadBackups {
+					path: watch_file.to_owned(),
+					source,
+				})?,
+			}
+		}
+		self.last_backups.insert(
+			watch_file.to_owned(),
+			LastBackup {
+				time: Instant::now(),
+				hash: hash.clone(),
+			},
+		);
+
+		self.record_hash(watch_file, &hash, &timestamp)?;
+
+		if let Some(on_change) = &self.config.on_change {
+			let backup = backup_file.to_string_lossy();
+			let command = hook::fill_placeholders(
+				on_change,
+				&[
+					("file", watch_file),
+					("backup", &backup),
+					("timestamp", &timestamp),
+					("hash", &hash),
+				],
+			);
+			let env = [
+				("WATCH_FILE", watch_file),
+				("WATCH_BACKUP", &backup),
+				("WATCH_TIMESTAMP", &timestamp),
+				("WATCH_HASH", &hash),
+			];
+			match hook::run_hook(&command, &env, self.config.hook_timeout) {
+				Ok(Some(status)) if !status.success() => {
+					self.report_error(WatchError::HookFailed {
+						command: on_change.clone(),
+						status,
+					})?
+				}
+				Ok(Some(_)) => {}
+				Ok(None) => self.report_error(WatchError::HookTimedOut {
+					command: on_change.clone(),
+				})?,
+				Err(source) => self.report_error(WatchError::Hook {
+					command: on_change.clone(),
+					source,
+				})?,
+			}
+		}
+
+		if let Some(desktop) = &mut self.desktop {
+			desktop.backup(watch_file, &backup_file);
+		}
+		if let Some(webhook) = &self.config.webhook {
+			let body = serde_json::json!({
+				"file": watch_file,
+				"backup": backup_file,
+				"timestamp": timestamp,
+				"hash": hash,
+				"size": size,
+			});
+			// A webhook that can't be reached shouldn't get in the way of watching
+			if let Err(message) = webhook.send(&body) {
+				let error = WatchError::Webhook {
+					url: webhook.url.clone(),
+					message,
+				};
+				self.warn(&error.to_string());
+			}
+		}
+		// Like the webhook, an upload that doesn't work shouldn't get in the way of watching. The
+		// backup is sent over SFTP first, since it can be removed once it's uploaded to S3
+		if let Some(sftp) = &mut self.sftp {
+			let destination = sftp.destination.to_string();
+			match sftp.upload(&backup_file, &remote_name(&self.config, &backup_file)) {
+				Ok(remote_path) => self.detail(
+					1,
+					&format!(
+						"Sent {} to {} over SFTP.",
+						backup_file.display(),
+						remote_path
+					),
+				),
+				Err(message) => {
+					let error = WatchError::Upload {
+						path: backup_file.to_string_lossy().into_owned(),
+						destination,
+						message,
+					};
+					self.warn(&error.to_string());
+				}
+			}
+		}
+		if let Some(s3) = self.config.s3.clone() {
+			let key = s3.key_for(&remote_name(&self.config, &backup_file));
+			match s3.upload(&backup_file, &key) {
+				Ok(()) => {
+					self.detail(
+						1,
+						&format!(
+							"Uploaded {} to s3://{}/{}.",
+							backup_file.display(),
+							s3.bucket,
+							key
+						),
+					);
+					if self.config.s3_delete_local {
+						match fs::remove_file(&backup_file) {
+							Ok(()) => {
+								let remote = PathBuf::from(format!("s3://{}/{}", s3.bucket, key));
+								self.moved_backup(
+									watch_file,
+									&hash,
+									&timestamp,
+									&backup_file,
+									&remote,
+								)?;
+								backup_file = remote;
+							}
+							Err(source) => self.warn(&format!(
+								"Unable to remove {} after uploading it: {}",
+								backup_file.display(),
+								source
+							)),
+						}
+					}
+				}
+				Err(message) => {
+					let error = WatchError::Upload {
+						path: backup_file.to_string_lossy().into_owned(),
+						destination: format!("s3://{}", s3.bucket),
+						message,
+					};
+					self.warn(&error.to_string());
+				}
+			}
+		}
+
+		// Get rid of any backups beyond the limits
+		self.prune(target)?;
+
+		Ok(Some(BackupResult {
+			watch_file: watch_file.to_owned(),
+			backup_file,
+			timestamp,
+			hash,
+			initial: cached_hash.is_none(),
+			manual: self.forcing,
+		}))
+	}
+
+	/// Points everything that refers to a backup at where it's been moved to, once the local copy
+	/// is gone.
+	///
+	/// The manifest can only be added to, so the backup gets another entry with its new location.
+	fn moved_backup(
+		&mut self,
+		watch_file: &str,
+		hash: &str,
+		timestamp: &str,
+		from: &Path,
+		to: &Path,
+	) -> Result<(), WatchError> {
+		if self.session.newest_backup.as_deref() == Some(from) {
+			self.session.newest_backup = Some(to.to_path_buf());
+		}
+		// Duplicates can't be linked to a backup that isn't there
+		if let Some(backup_hashes) = self.backup_hashes.get_mut(watch_file) {
+			backup_hashes.remove(hash);
+		}
+		if let Some(manifest) = &self.config.manifest {
+			let stamp = match self.numbers.get(watch_file) {
+				Some(&number) if self.config.name_template.is_numbered() => format_number(number),
+				_ => timestamp.to_owned(),
+			};
+			let entry = fs::metadata(watch_file).map(|metadata| ManifestEntry {
+				timestamp: stamp,
+				file: watch_file.to_owned(),
+				backup: to.to_path_buf(),
+				hash: hash.to_owned(),
+				algorithm: self.config.hash.algorithm.name().to_owned(),
+				size: metadata.len(),
+			});
+			if let Err(e) = entry.and_then(|entry| manifest::append(manifest, &entry)) {
+				self.report_error(WatchError::Manifest(e))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Says where the target would be backed up to, and carries on as if it was, without writing
+	/// anything.
+	fn pretend_backup(
+		&mut self,
+		target: &WatchTarget,
+		location: &BackupLocation,
+		hash: &str,
+		timestamp: &str,
+	) -> Result<Option<BackupResult>, WatchError> {
+		let watch_file = target.path.as_str();
+		let stamp = if self.config.name_template.is_numbered() {
+			let number = self.next_number(target, location, hash)?;
+			self.numbers.insert(watch_file.to_owned(), number);
+			format_number(number)
+		} else {
+			timestamp.to_owned()
+		};
+		let backup_file =
+			location.backup_path(&stamp, hash, &self.config.encoding().extension(), 0);
+		self.say(&format!(
+			"Would back up {} to {}.",
+			watch_file,
+			backup_file.display()
+		));
+		self.last_backups.insert(
+			watch_file.to_owned(),
+			LastBackup {
+				time: Instant::now(),
+				hash: hash.to_owned(),
+			},
+		);
+		self.record_hash(watch_file, hash, timestamp)?;
+		Ok(None)
+	}
+
+	/// Watches until told to shut down through a [`Controller`] or one of the limits is reached,
+	/// falling back to polling if filesystem events aren't available.
+	///
+	/// This only returns an error if the watcher is strict.
+	pub fn run(&mut self) -> Result<StopReason, WatchError> {
+		self.systemd = Systemd::from_env();
+		let reason = match self.config.watch_mode {
+			WatchMode::Events => match self.watch_events() {
+				Ok(event_watcher) => {
+					self.event_watcher = Some(event_watcher);
+					self.say("Watching for filesystem events.");
+					self.print_event(OutputEvent::Started { mode: "events" });
+					if let Some(systemd) = &self.systemd {
+						systemd.ready();
+					}
+					let result = self.handle_events();
+					self.event_watcher = None;
+					result
+				}
+				Err(e) => {
+					self.warn(&format!(
+						"Unable to watch for filesystem events ({}), falling back to polling.",
+						e
+					));
+					self.poll()
+				}
+			},
+			WatchMode::Poll => self.poll(),
+		}?;
+		if let Some(systemd) = &self.systemd {
+			systemd.stopping();
+		}
+		if self.config.final_backup {
+			self.say("Checking for changes one last time before stopping.");
+			self.finishing = true;
+			self.check_once()?;
+		}
+		match reason {
+			StopReason::Shutdown => {}
+			StopReason::RunFor => self.say(&format!(
+				"Reached the --run-for limit, stopping after backing up {} change(s).",
+				self.change_count
+			)),
+			StopReason::MaxChanges => self.say(&format!(
+				"Reached the --max-changes limit, stopping after backing up {} change(s).",
+				self.change_count
+			)),
+		}
+		self.report_session();
+		self.print_event(OutputEvent::Shutdown {
+			reason: reason.name(),
+			changes: self.change_count,
+		});
+		Ok(reason)
+	}
+
+	/// Reports how the session went, once the watcher has stopped.
+	fn report_session(&mut self) {
+		let ran_for = self.started.elapsed();
+		let newest_backup = self.session.newest_backup.clone();
+		let mut message = format!(
+			"Ran for {} with {} check(s), making {} backup(s) ({} written).",
+			format_duration(ran_for),
+			self.session.checks,
+			self.session.backups,
+			format_size(self.session.bytes_written)
+		);
+		if let Some(newest_backup) = &newest_backup {
+			message.push_str(&format!(" The newest is {}.", newest_backup.display()));
+		}
+		self.say(&message);
+		self.print_event(OutputEvent::Summary {
+			ran_for_ms: ran_for.as_millis() as u64,
+			checks: self.session.checks,
+			backups: self.session.backups,
+			bytes_written: self.session.bytes_written,
+			newest_backup: newest_backup.as_deref(),
+		});
+	}
+
+	/// Switches to a new config while running, keeping everything that's known about the targets.
+	///
+	/// Whatever can't change while running (like which files are watched) stays as it was, with a
+	/// warning if the new config tried to change it.
+	pub fn reload(&mut self, mut config: WatchConfig) {
+		let mut kept = Vec::new();
+		let retarget = config.watch_patterns != self.config.watch_patterns;
+		if config.recursive != self.config.recursive {
+			kept.push("whether to watch recursively");
+			config.recursive = self.config.recursive;
+		}
+		if config.follow_symlinks != self.config.follow_symlinks {
+			kept.push("whether to follow symlinks");
+			config.follow_symlinks = self.config.follow_symlinks;
+		}
+		if config.watch_mode != self.config.watch_mode {
+			kept.push("the watch mode");
+			config.watch_mode = self.config.watch_mode;
+		}
+		if config.hash.algorithm.name() != self.config.hash.algorithm.name() {
+			kept.push("the hash algorithm");
+			config.hash.algorithm = self.config.hash.algorithm;
+		}
+		if config.state_file != self.config.state_file {
+			kept.push("the state file");
+			config.state_file = self.config.state_file.take();
+		}
+		if config.log_file != self.config.log_file {
+			kept.push("the log file");
+			config.log_file = self.config.log_file.take();
+		}
+		if config.format != self.config.format {
+			kept.push("the output format");
+			config.format = self.config.format;
+		}
+		for kept in kept {
+			self.warn(&format!(
+				"Unable to change {} while watching, so it's staying as it was.",
+				kept
+			));
+		}
+
+		if config.notify_desktop != self.config.notify_desktop {
+			self.desktop = config.notify_desktop.then(DesktopNotifier::new);
+		}
+		if config.sftp != self.config.sftp {
+			self.sftp = config.sftp.clone().map(SftpUploader::new);
+		}
+		if config.threads != self.config.threads {
+			self.pool = None;
+		}
+		if let Some(log_file) = &mut self.log_file {
+			log_file.set_rotation(config.log_rotation);
+		}
+		// Backups may be somewhere else or named differently now
+		self.backup_hashes.clear();
+		self.numbers.clear();
+		self.config = config;
+		// Only changes made to the new files from now on are backed up, the same as when starting
+		if retarget {
+			if let Err(e) = self.cache_hashes() {
+				self.warn(&e.to_string());
+			}
+		}
+		// The new files need their directories watched for events too
+		if retarget && self.event_watcher.is_some() {
+			match self.watch_events() {
+				Ok(event_watcher) => self.event_watcher = Some(event_watcher),
+				Err(e) => self.warn(&format!(
+					"Unable to watch for events on the new files ({}), so they'll only be checked \
+					 along with the others.",
+					e
+				)),
+			}
+		}
+		self.say("Reloaded the config.");
+	}
+
+	/// The interval to start polling at, which is within the adaptive range if there is one.
+	fn starting_interval(&self) -> time::Duration {
+		match &self.config.adaptive {
+			Some(adaptive) => self.config.interval.clamp(adaptive.min, adaptive.max),
+			None => self.config.interval,
+		}
+	}
+
+	/// Checks the targets on an interval, which may adapt to how often they change.
+	fn poll(&mut self) -> Result<StopReason, WatchError> {
+		let mut interval = self.starting_interval();
+		match &self.config.adaptive {
+			Some(adaptive) => {
+				let message = format!(
+					"Polling for changes every {} to {}, depending on how often they happen.",
+					format_duration(adaptive.min),
+					format_duration(adaptive.max)
+				);
+				self.say(&message);
+			}
+			None => self.say(&format!(
+				"Polling for changes every {}ms.",
+				interval.as_millis()
+			)),
+		}
+		self.print_event(OutputEvent::Started { mode: "poll" });
+		if let Some(systemd) = &self.systemd {
+			systemd.ready();
+		}
+		let mut next_check = Instant::now() + interval;
+		loop {
+			if let Some(reason) = self.limit_reached() {
+				return Ok(reason);
+			}
+			// There's nothing to wait for while paused but being told to resume
+			let until = if self.paused { None } else { Some(next_check) };
+			match self.recv_until(until) {
+				Ok(Message::Control(control)) => {
+					let restarting = matches!(control, Control::Reload(_) | Control::Resume);
+					if let Some(reason) = self.handle_control(control)? {
+						return Ok(reason);
+					}
+					// A new interval takes effect right away, rather than after the next check
+					if restarting {
+						interval = self.starting_interval();
+						next_check = Instant::now() + interval;
+					}
+				}
+				Ok(Message::Event(_)) => {}
+				Err(RecvTimeoutError::Timeout) => {
+					// The timeout may have been cut short by the run_for deadline
+					if Instant::now() >= next_check {
+						let changed = !self.check_once()?.is_empty();
+						if let Some(adaptive) = &self.config.adaptive {
+							let next_interval = adaptive.next(interval, changed);
+							if next_interval != interval {
+								interval = next_interval;
+								self.detail(
+									2,
+									&format!("Polling every {} now.", format_duration(interval)),
+								);
+							}
+						}
+						next_check += interval;
+					}
+				}
+				Err(RecvTimeoutError::Disconnected) => return Ok(StopReason::Shutdown),
+			}
+		}
+	}
+
+	/// Sets up filesystem watches covering every watch pattern, which send their events to the
+	/// watcher.
+	fn watch_events(&mut self) -> notify::Result<RecommendedWatcher> {
+		let sender = self.sender.clone();
+		let mut watcher = notify::recommended_watcher(move |event| {
+			let _ = sender.send(Message::Event(event));
+		})?;
+		for pattern in &self.config.watch_patterns {
+			let (watch_dir, recursive_mode) = events::watch_dir_for(pattern, self.config.recursive);
+			watcher.watch(&watch_dir, recursive_mode)?;
+		}
+		// Files that symlinks point to can be somewhere else entirely, so their directories need
+		// watching too (symlinks that are broken are left for the polling fallback to notice)
+		if self.config.follow_symlinks {
+			for target in self.resolve_targets() {
+				if let Some(real_dir) = events::symlink_target_dir(Path::new(&target.path)) {
+					let _ = watcher.watch(&real_dir, RecursiveMode::NonRecursive);
+				}
+			}
+		}
+		Ok(watcher)
+	}
+
+	/// Checks the targets whenever a relevant filesystem event comes in.
+	///
+	/// Once an event is received, the check waits until no more events have arrived for the
+	/// debounce period, so that something like an editor doing several writes in a row only
+	/// results in one backup.
+	fn handle_events(&mut self) -> Result<StopReason, WatchError> {
+		loop {
+			if let Some(reason) = self.limit_reached() {
+				return Ok(reason);
+			}
+			let cooldown_deadline = self.cooldown_deadline();
+			match self.recv_until(cooldown_deadline) {
+				Ok(Message::Event(event)) => {
+					if self.paused || !events::is_relevant_event(&self.config, &event) {
+						continue;
+					}
+					self.removed.extend(events::removed_paths(&event));
+				}
+				Ok(Message::Control(control)) => {
+					if let Some(reason) = self.handle_control(control)? {
+						return Ok(reason);
+					}
+					continue;
+				}
+				Err(RecvTimeoutError::Timeout) => {
+					// Changes held off by the cooldown need a check of their own once it's over
+					if !self.paused
+						&& cooldown_deadline.is_some_and(|deadline| Instant::now() >= deadline)
+					{
+						self.check_once()?;
+					}
+					continue;
+				}
+				Err(RecvTimeoutError::Disconnected) => return Ok(StopReason::Shutdown),
+			}
+			loop {
+				match self.recv_until(Some(Instant::now() + self.config.debounce)) {
+					Ok(Message::Event(event)) => {
+						self.removed.extend(events::removed_paths(&event));
+						continue;
+					}
+					Ok(Message::Control(control)) => {
+						if let Some(reason) = self.handle_control(control)? {
+							return Ok(reason);
+						}
+					}
+					Err(RecvTimeoutError::Timeout) => break,
+					Err(RecvTimeoutError::Disconnected) => return Ok(StopReason::Shutdown),
+				}
+			}
+			// Pausing partway through waiting for events to settle leaves them for after resuming
+			if !self.paused {
+				self.check_once()?;
+			}
+		}
+	}
+
+	/// Acts on an instruction sent through a [`Controller`], returning why to stop if it's to
+	/// stop.
+	fn handle_control(&mut self, control: Control) -> Result<Option<StopReason>, WatchError> {
+		match control {
+			Control::Shutdown => return Ok(Some(StopReason::Shutdown)),
+			Control::Backup => {
+				self.backup_now()?;
+			}
+			Control::Reload(config) => self.reload(*config),
+			Control::Status => self.report_status(),
+			Control::Pause => {
+				if !self.paused {
+					self.paused = true;
+					self.say_in(
+						Color::Yellow,
+						"Paused - changes won't be backed up until resuming.",
+					);
+				}
+			}
+			Control::Resume => {
+				if self.paused {
+					self.paused = false;
+					self.say("Resumed.");
+					self.check_once()?;
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	/// Reports the latest hash, when it was last backed up, and how many backups there are of
+	/// each target.
+	///
+	/// This is reported even when being quiet, since it's only ever asked for.
+	fn report_status(&mut self) {
+		if self.paused && self.config.format == OutputFormat::Text {
+			println!("Paused.");
+		}
+		for target in self.resolve_targets() {
+			let watch_file = target.path.as_str();
+			let hash = self.cached_hashes.get(watch_file).cloned();
+			let last_backup = self
+				.last_backups
+				.get(watch_file)
+				.map(|last_backup| last_backup.time.elapsed());
+			let backups = self.backups(&target).map_or(0, |backups| backups.len());
+			if self.config.format == OutputFormat::Json {
+				let event = OutputEvent::Status {
+					file: watch_file,
+					hash: hash.as_deref(),
+					last_backup_ms: last_backup.map(|ago| ago.as_millis() as u64),
+					backups,
+					paused: self.paused,
+				};
+				println!("{}", event.to_json());
+				continue;
+			}
+			let last_backup = last_backup.map_or_else(
+				|| String::from("not backed up since starting"),
+				|ago| format!("last backed up {} ago", format_duration(ago)),
+			);
+			println!(
+				"{}: {}, {}, {} backup(s).",
+				watch_file,
+				hash.as_deref().unwrap_or("not hashed yet"),
+				last_backup,
+				backups
+			);
+		}
+	}
+
+	/// Waits for the next message until the given time, or until the `run_for` deadline if that
+	/// comes first.
+	///
+	/// The systemd watchdog is pinged in the meantime, if there is one.
+	fn recv_until(&self, until: Option<Instant>) -> Result<Message, RecvTimeoutError> {
+		let deadline = self.config.run_for.map(|run_for| self.started + run_for);
+		let until = match (until, deadline) {
+			(Some(until), Some(deadline)) => Some(until.min(deadline)),
+			(until, deadline) => until.or(deadline),
+		};
+		loop {
+			let ping = self.systemd.as_ref().and_then(Systemd::next_ping);
+			let wait_until = match (until, ping) {
+				(Some(until), Some(ping)) => until.min(ping),
+				(until, ping) => match until.or(ping) {
+					Some(wait_until) => wait_until,
+					None => {
+						return self
+							.receiver
+							.recv()
+							.map_err(|_| RecvTimeoutError::Disconnected)
+					}
+				},
+			};
+			match self
+				.receiver
+				.recv_timeout(wait_until.saturating_duration_since(Instant::now()))
+			{
+				Err(RecvTimeoutError::Timeout)
+					if until.is_none_or(|until| Instant::now() < until) =>
+				{
+					if let Some(systemd) = &self.systemd {
+						systemd.ping_watchdog();
+					}
+				}
+				result => return result,
+			}
+		}
+	}
+
+	/// Which of the limits on how long to run for has been reached, if any.
+	fn limit_reached(&self) -> Option<StopReason> {
+		if self
+			.config
+			.max_changes
+			.is_some_and(|max_changes| self.change_count >= max_changes)
+		{
+			Some(StopReason::MaxChanges)
+		} else if self
+			.config
+			.run_for
+			.is_some_and(|run_for| self.started.elapsed() >= run_for)
+		{
+			Some(StopReason::RunFor)
+		} else {
+			None
+		}
+	}
+
+	/// Whether the file's content has already been backed up (or was there from the start).
+	fn is_up_to_date(&self, watch_file: &str, hash: &str) -> bool {
+		self.cached_hashes
+			.get(watch_file)
+			.is_some_and(|cached_hash| cached_hash == hash)
+			&& self
+				.last_backups
+				.get(watch_file)
+				.is_none_or(|last_backup| last_backup.hash == hash)
+	}
+
+	/// When the earliest cooldown with a change waiting on it is over, if there is one.
+	fn cooldown_deadline(&self) -> Option<Instant> {
+		let cooldown = self.config.cooldown?;
+		self.last_backups
+			.iter()
+			.filter(|(watch_file, last_backup)| {
+				self.cached_hashes.get(*watch_file) != Some(&last_backup.hash)
+			})
+			.map(|(_, last_backup)| last_backup.time + cooldown)
+			.min()
+	}
+
+	/// Hashes a watched file, unless its size and modification time are the same as when it was
+	/// last hashed, in which case that hash is reused instead.
+	///
+	/// Targets that were already hashed ahead of time use that hash.
+	fn hash_target_cheaply(&mut self, watch_file: &str) -> Result<String, WatchError> {
+		if let Some(prehashed) = self.prehashed.remove(watch_file) {
+			if let Some(stats) = &prehashed.stats {
+				self.report_stats(watch_file, stats);
+			}
+			self.detail(
+				2,
+				&format!("Hashed {} in {:.1?}.", watch_file, prehashed.took),
+			);
+			let hash = prehashed.hash?;
+			if let Some(stats) = prehashed.stats {
+				self.seen
+					.insert(watch_file.to_owned(), (stats, hash.clone()));
+			}
+			return Ok(hash);
+		}
+		if !self.config.fast_path {
+			return self.hash_target(watch_file);
+		}
+		let stats = file_stats(&self.config, watch_file);
+		if let Some(seen_hash) = self.seen_hash(watch_file, stats.as_ref()) {
+			let hash = seen_hash.clone();
+			self.detail(
+				2,
+				&format!(
+					"Skipped hashing {}, since its size and modification time haven't changed.",
+					watch_file
+				),
+			);
+			return Ok(hash);
+		}
+
+		if let Some(stats) = &stats {
+			self.report_stats(watch_file, stats);
+		}
+
+		// The stats are taken before hashing, so a change that happens partway through is still
+		// picked up next time
+		let hash = self.hash_target(watch_file)?;
+		if let Some(stats) = stats {
+			self.seen
+				.insert(watch_file.to_owned(), (stats, hash.clone()));
+		}
+		Ok(hash)
+	}
+
+	/// Notes the size and modification time of a target that's about to be hashed, since they're
+	/// what tells whether it might have changed.
+	fn report_stats(&mut self, watch_file: &str, stats: &FileStats) {
+		let modified = stats.modified.map_or_else(
+			|| String::from("an unknown time"),
+			|modified| log::format_time(modified, self.config.local_time),
+		);
+		let message = format!(
+			"{} is {}, and was last modified at {}.",
+			watch_file,
+			format_size(stats.len),
+			modified
+		);
+		self.detail(2, &message);
+	}
+
+	/// The hash a target had when it was last hashed, if its stats show that it can't have
+	/// changed since.
+	fn seen_hash(&self, watch_file: &str, stats: Option<&FileStats>) -> Option<&String> {
+		let stats = stats?;
+		let (seen_stats, seen_hash) = self.seen.get(watch_file)?;
+		// Without a modification time there's no telling whether the file was touched
+		(stats.modified.is_some() && stats == seen_stats).then_some(seen_hash)
+	}
+
+	/// Hashes a watched file, retrying if it's locked.
+	fn hash_target(&mut self, watch_file: &str) -> Result<String, WatchError> {
+		let started = Instant::now();
+		let hash = hash_contents(&self.config, watch_file)?;
+		self.detail(
+			2,
+			&format!("Hashed {} in {:.1?}.", watch_file, started.elapsed()),
+		);
+		Ok(hash)
+	}
+
+	/// Finds an existing backup of the target with the given hash, if there is one.
+	///
+	/// The existing backups are hashed the first time this is called for each target, skipping
+	/// over any that can't be read.
+	fn existing_backup(&mut self, target: &WatchTarget, hash: &str) -> Option<PathBuf> {
+		if !self.backup_hashes.contains_key(&target.path) {
+			let mut backup_hashes = HashMap::new();
+			for backup in self.backups(target).unwrap_or_default() {
+				if let Ok(backup_hash) = self.backup_hash(&backup) {
+					backup_hashes.insert(backup_hash, backup.path);
+				}
+			}
+			self.backup_hashes
+				.insert(target.path.clone(), backup_hashes);
+		}
+
+		// The backup may have been pruned since it was hashed
+		let backup_hashes = self.backup_hashes.get_mut(&target.path)?;
+		let existing = backup_hashes.get(hash)?.clone();
+		if fs::symlink_metadata(&existing).is_err() {
+			backup_hashes.remove(hash);
+			return None;
+		}
+		Some(existing)
+	}
+
+	/// Runs the `on_error` command and shows a desktop notification for the error, unless that
+	/// was already done for the same error within `on_error_interval`.
+	///
+	/// Problems with the command itself are only printed, so that they can't set it off again.
+	fn alert_error(&mut self, error: &WatchError) {
+		if self.config.on_error.is_none() && self.desktop.is_none() {
+			return;
+		}
+		let message = error.to_string();
+		if self
+			.error_alerts
+			.get(&message)
+			.is_some_and(|last_alert| last_alert.elapsed() < self.config.on_error_interval)
+		{
+			return;
+		}
+		self.error_alerts.insert(message.clone(), Instant::now());
+
+		if let Some(desktop) = &mut self.desktop {
+			desktop.error(&message);
+		}
+		let on_error = match &self.config.on_error {
+			Some(on_error) => on_error,
+			None => return,
+		};
+
+		let path = error.path().unwrap_or_default();
+		let command = hook::fill_placeholders(
+			on_error,
+			&[
+				("kind", error.kind()),
+				("message", &message),
+				("file", path),
+			],
+		);
+		let env = [
+			("WATCH_ERROR_KIND", error.kind()),
+			("WATCH_ERROR", &message),
+			("WATCH_FILE", path),
+		];
+		let hook_error = match hook::run_hook(&command, &env, self.config.hook_timeout) {
+			Ok(Some(status)) if !status.success() => WatchError::HookFailed {
+				command: on_error.clone(),
+				status,
+			},
+			Ok(Some(_)) => return,
+			Ok(None) => WatchError::HookTimedOut {
+				command: on_error.clone(),
+			},
+			Err(source) => WatchError::Hook {
+				command: on_error.clone(),
+				source,
+			},
+		};
+		self.warn(&hook_error.to_string());
+	}
+
+	/// Writes a backup of the target with the given hash, returning the path it was written to.
+	fn write_backup(
+		&mut self,
+		target: &WatchTarget,
+		location: &BackupLocation,
+		hash: &str,
+		timestamp: &str,
+	) -> Result<PathBuf, WatchError> {
+		let watch_file = target.path.as_str();
+		fs::create_dir_all(location.backup_dir()).map_err(|source| WatchError::CreateDir {
+			path: watch_file.to_owned(),
+			source,
+		})?;
+		let number = if self.config.name_template.is_numbered() {
+			Some(self.next_number(target, location, hash)?)
+		} else {
+			None
+		};
+		let stamp = number.map_or_else(|| timestamp.to_owned(), format_number);
+		let base = self
+			.config
+			.delta
+			.and_then(|full_every| delta_base(location, full_every));
+		let started = Instant::now();
+
+		// Content that's already in the store only needs a hardlink to it, rather than a copy
+		let store =
+			(self.config.hardlink_dedup && base.is_none()).then(|| self.store_for(location));
+		let object = store.as_ref().map(|store| {
+			store.object_path(
+				self.config.hash.algorithm.name(),
+				hash,
+				&self.config.encoding().extension(),
+			)
+		});
+		let mut attempts = 0;
+		let mut cloned = false;
+		let backup_file = loop {
+			let linked = object
+				.as_ref()
+				.filter(|object| object.is_file())
+				.and_then(|object| {
+					location
+						.link_backup(object, &stamp, hash, &self.config.encoding().extension())
+						.ok()
+				});
+			let backup_file = match linked {
+				Some(backup_file) => backup_file,
+				None => {
+					let (backup_file, was_cloned) = self
+						.config
+						.copy_retry
+						.run(|| {
+							location.write_backup(
+								Path::new(watch_file),
+								&stamp,
+								hash,
+								self.config.encoding(),
+								base.as_deref(),
+								self.config.preserve,
+							)
+						})
+						.map_err(|source| WatchError::Copy {
+							path: watch_file.to_owned(),
+							source,
+						})?;
+					cloned = was_cloned;
+					// Without hardlinks, every backup is just a copy of its own like usual
+					if let (Some(store), Some(object)) = (&store, &object) {
+						if let Err(e) = store.add(object, &backup_file) {
+							self.detail(
+								2,
+								&format!(
+									"Unable to add {} to the hardlink store: {}",
+									backup_file.display(),
+									e
+								),
+							);
+						}
+					}
+					backup_file
+				}
+			};
+			if !self.config.verify {
+				break backup_file;
+			}
+			if let Some(elapsed) = self.verify_backup(watch_file, &backup_file, hash) {
+				self.detail(
+					2,
+					&format!("Verified {} in {:.1?}.", backup_file.display(), elapsed),
+				);
+				break backup_file;
+			}
+
+			// A bad backup is made again once, in case it was just a bad write
+			let _ = fs::remove_file(&backup_file);
+			if let Some(object) = &object {
+				let _ = fs::remove_file(object);
+			}
+			attempts += 1;
+			if attempts == 2 {
+				return Err(WatchError::Verify {
+					path: watch_file.to_owned(),
+					backup: backup_file,
+				});
+			}
+			self.warn(&format!(
+				"The backup {} doesn't match {}, so it's being made again.",
+				backup_file.display(),
+				watch_file
+			));
+		};
+		if let Some(number) = number {
+			self.numbers.insert(watch_file.to_owned(), number);
+		}
+		let written = fs::metadata(&backup_file).map_or(0, |metadata| metadata.len());
+		self.session.backups += 1;
+		self.session.bytes_written += written;
+		self.session.newest_backup = Some(backup_file.clone());
+		self.detail(
+			2,
+			&format!(
+				"{} {} ({}) in {:.1?}.",
+				if cloned { "Cloned" } else { "Wrote" },
+				backup_file.display(),
+				format_size(written),
+				started.elapsed()
+			),
+		);
+
+		if let Some(manifest) = &self.config.manifest {
+			let entry = fs::metadata(watch_file).map(|metadata| ManifestEntry {
+				timestamp: stamp,
+				file: watch_file.to_owned(),
+				backup: backup_file.clone(),
+				hash: hash.to_owned(),
+				algorithm: self.config.hash.algorithm.name().to_owned(),
+				size: metadata.len(),
+			});
+			if let Err(e) = entry.and_then(|entry| manifest::append(manifest, &entry)) {
+				self.report_error(WatchError::Manifest(e))?;
+			}
+		}
+		Ok(backup_file)
+	}
+
+	/// Checks whether the backup's content (once decompressed or rebuilt) has the hash it's supposed
+	/// to, returning how long that took if it does.
+	fn verify_backup(
+		&self,
+		watch_file: &str,
+		backup_file: &Path,
+		hash: &str,
+	) -> Option<time::Duration> {
+		let started = Instant::now();
+		let backup_hash = hash_backup(backup_file, self.config.hash.algorithm)
+			.map_err(|source| WatchError::Hash {
+				path: watch_file.to_owned(),
+				source,
+			})
+			.and_then(|backup_hash| with_link(&self.config, watch_file, backup_hash));
+		match backup_hash {
+			Ok(backup_hash) if backup_hash == hash => Some(started.elapsed()),
+			_ => None,
+		}
+	}
+
+	/// Works out the number of the next backup of the target when numbering backups.
+	///
+	/// The first backup of each target made by the watcher continues on from the highest number
+	/// among the existing backups, and numbers that are somehow already taken are skipped over.
+	fn next_number(
+		&self,
+		target: &WatchTarget,
+		location: &BackupLocation,
+		hash: &str,
+	) -> Result<u64, WatchError> {
+		let last = match self.numbers.get(&target.path) {
+			Some(&last) => last,
+			None => location
+				.find_backups()
+				.map_err(|source| WatchError::ReadBackups {
+					path: target.path.clone(),
+					source,
+				})?
+				.iter()
+				.filter_map(|backup| backup.timestamp.parse::<u64>().ok())
+				.max()
+				.unwrap_or(0),
+		};
+		let mut number = last + 1;
+		while location
+			.backup_path(
+				&format_number(number),
+				hash,
+				&self.config.encoding().extension(),
+				0,
+			)
+			.exists()
+		{
+			number += 1;
+		}
+		Ok(number)
+	}
+
+	/// Reports an error, only passing it on when the watcher is strict.
+	///
+	/// Most errors are transient (like a file being locked for a moment by another program), so
+	/// the affected file is simply tried again on the next check.
+	fn report_error(&mut self, error: WatchError) -> Result<(), WatchError> {
+		// A file that's missing for a single check is most likely just being saved
+		let transient = match &error {
+			WatchError::Hash { path, .. } if error.is_missing_file() => self
+				.failed_checks
+				.get(path)
+				.is_none_or(|&checks| checks < 2),
+			_ => false,
+		};
+		if !transient {
+			self.alert_error(&error);
+		}
+		self.print_event(OutputEvent::Error {
+			kind: error.kind(),
+			message: &error.to_string(),
+			file: error.path(),
+		});
+
+		if self.config.strict {
+			return Err(error);
+		}
+		if error.is_missing_file() {
+			let message = format!("{}, checking again later.", error);
+			if self.config.quiet {
+				self.log_text(&message);
+			} else {
+				self.warn(&message);
+			}
+		} else {
+			self.warn(&error.to_string());
+		}
+		self.error_count += 1;
+		Ok(())
+	}
+
+	/// Remembers the latest hash of a file, persisting it to the state file if there is one.
+	fn record_hash(
+		&mut self,
+		watch_file: &str,
+		hash: &str,
+		timestamp: &str,
+	) -> Result<(), WatchError> {
+		self.cached_hashes
+			.insert(watch_file.to_owned(), hash.to_owned());
+
+		// Nothing was backed up on a dry run, so there's nothing for the next run to know about
+		if self.config.dry_run {
+			return Ok(());
+		}
+		if let Some(state_file) = &self.config.state_file {
+			self.state.files.insert(
+				watch_file.to_owned(),
+				FileState {
+					hash: hash.to_owned(),
+					timestamp: timestamp.to_owned(),
+				},
+			);
+			if let Err(e) = self.state.save(state_file) {
+				self.report_error(WatchError::State(e))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Deletes any of the target's backups that are beyond the limits in the config - older than
+	/// `keep_for`, beyond `max_backups` or `max_total_size`, or not kept by the retention policy.
+	///
+	/// When multiple limits are set, backups are deleted until all of them are met.
+	pub fn prune(&mut self, target: &WatchTarget) -> Result<(), WatchError> {
+		if self.config.dry_run {
+			return Ok(());
+		}
+		self.prune_expired(target)?;
+
+		let location = self.backup_location(target);
+		if let Some(retention) = &self.config.retention {
+			let pruned = backup::prune_by_retention(&location, retention, Utc::now());
+			self.report_pruned(&target.path, pruned)?;
+		}
+		if let Some(max_backups) = self.config.max_backups {
+			let pruned = backup::prune_to_count(&location, max_backups);
+			self.report_pruned(&target.path, pruned)?;
+		}
+		if let Some(max_total_size) = self.config.max_total_size {
+			let pruned = backup::prune_to_size(&location, max_total_size);
+			if let Ok((_, true)) = pruned {
+				self.warn(&format!(
+					"The newest backup of {} alone exceeds the maximum total size, keeping it \
+					 anyways.",
+					target.path
+				));
+			}
+			self.report_pruned(&target.path, pruned.map(|(pruned, _)| pruned))?;
+		}
+
+		// Content that isn't in any backups anymore doesn't need to be kept in the store either
+		if self.config.hardlink_dedup {
+			if let Err(source) = self.store_for(&location).remove_unused() {
+				self.report_error(WatchError::Prune {
+					path: target.path.clone(),
+					source,
+				})?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Deletes any of the target's backups that are older than the `keep_for` duration.
+	fn prune_expired(&mut self, target: &WatchTarget) -> Result<(), WatchError> {
+		let keep_for = match self.config.keep_for {
+			Some(keep_for) if !self.config.dry_run => keep_for,
+			_ => return Ok(()),
+		};
+		let location = self.backup_location(target);
+		let keep_newest =
+			self.config.on_delete == OnDelete::BackupLast && self.deleted.contains(&target.path);
+		let pruned = backup::prune_older_than(&location, Utc::now() - keep_for, keep_newest);
+		self.report_pruned(&target.path, pruned)
+	}
+
+	/// Notes which file is at the target's path, mentioning it when that's a different file than
+	/// last time.
+	///
+	/// Editors often save by writing a new file and renaming it over the old one, which is
+	/// handled the same as any other change since the target is always read through its path.
+	fn notice_replaced(&mut self, watch_file: &str) {
+		let identity = match fs::metadata(watch_file)
+			.ok()
+			.and_then(|m| file_identity(&m))
+		{
+			Some(identity) => identity,
+			None => return,
+		};
+		match self.identities.insert(watch_file.to_owned(), identity) {
+			Some(previous) if previous != identity => self.detail(
+				1,
+				&format!("{} was replaced by a different file.", watch_file),
+			),
+			_ => {}
+		}
+	}
+
+	/// Looks for the file that was at the target's path in the same directory, in case it's been
+	/// renamed, returning the target to check instead if it's being followed.
+	fn notice_renamed(&mut self, target: &WatchTarget) -> Option<WatchTarget> {
+		let watch_file = target.path.as_str();
+		let identity = *self.identities.get(watch_file)?;
+		let current = fs::metadata(watch_file)
+			.ok()
+			.and_then(|m| file_identity(&m));
+		if current == Some(identity) {
+			return None;
+		}
+		let new_path = find_file(Path::new(watch_file), identity)?;
+		self.identities.remove(watch_file);
+		match self.config.follow_rename? {
+			Follow::Path => {
+				self.say_in(
+					Color::Yellow,
+					&format!(
+						"{} was renamed to {}, still watching {}.",
+						watch_file, new_path, watch_file
+					),
+				);
+				None
+			}
+			Follow::Inode => {
+				self.say_in(
+					Color::Yellow,
+					&format!(
+						"{} was renamed to {}, now watching {}.",
+						watch_file, new_path, new_path
+					),
+				);
+				let named_after = target
+					.named_after
+					.clone()
+					.unwrap_or_else(|| target.path.clone());
+				self.followed.insert(named_after.clone(), new_path.clone());
+				self.move_target(watch_file, &new_path);
+				Some(WatchTarget {
+					path: new_path,
+					relative_path: target.relative_path.clone(),
+					named_after: Some(named_after),
+				})
+			}
+		}
+	}
+
+	/// Carries over what's known about a target to the path it's been renamed to.
+	fn move_target(&mut self, from: &str, to: &str) {
+		fn rename<T>(map: &mut HashMap<String, T>, from: &str, to: &str) {
+			if let Some(value) = map.remove(from) {
+				map.insert(to.to_owned(), value);
+			}
+		}
+		rename(&mut self.cached_hashes, from, to);
+		rename(&mut self.numbers, from, to);
+		rename(&mut self.last_backups, from, to);
+		rename(&mut self.backup_hashes, from, to);
+		rename(&mut self.seen, from, to);
+		rename(&mut self.identities, from, to);
+		self.failed_checks.remove(from);
+	}
+
+	/// Handles a watched file having been deleted, returning whether that should still be
+	/// reported as an error.
+	///
+	/// It's only reported the first time it's noticed, after which the file is quietly waited on.
+	fn notice_deleted(&mut self, target: &WatchTarget) -> Result<bool, WatchError> {
+		let watch_file = target.path.as_str();
+		if self.deleted.contains(watch_file) {
+			return Ok(false);
+		}
+		let message = match self.config.on_delete {
+			OnDelete::Exit => {
+				return Err(WatchError::Deleted {
+					path: watch_file.to_owned(),
+				})
+			}
+			OnDelete::Wait => format!("{} was deleted, waiting for it to come back.", watch_file),
+			OnDelete::BackupLast => format!(
+				"{} was deleted, waiting for it to come back (its newest backup won't be pruned \
+				 until then).",
+				watch_file
+			),
+		};
+		self.deleted.insert(watch_file.to_owned());
+		self.say_in(Color::Yellow, &message);
+		// The newest backup has the last content the file had before it was deleted
+		let last_backup = if self.config.backup_on_delete {
+			let newest = self
+				.backup_location(target)
+				.find_backups()
+				.ok()
+				.and_then(|mut backups| backups.pop())
+				.map(|backup| backup.path);
+			match &newest {
+				Some(path) => self.say(&format!(
+					"The last content of {} is in {}.",
+					watch_file,
+					path.display()
+				)),
+				None => self.say(&format!(
+					"There are no backups of the last content of {}.",
+					watch_file
+				)),
+			}
+			newest
+		} else {
+			None
+		};
+		self.print_event(OutputEvent::Deleted {
+			file: watch_file,
+			last_backup: last_backup.as_deref(),
+		});
+		Ok(true)
+	}
+
+	/// Whether messages for people should be printed, which they aren't when they're being quiet
+	/// or when the output is meant for other programs.
+	fn prints_text(&self) -> bool {
+		!self.config.quiet && self.config.format == OutputFormat::Text
+	}
+
+	/// Whether messages for people go anywhere at all, either printed or to the log file.
+	fn says_text(&self) -> bool {
+		self.prints_text() || (self.log_file.is_some() && self.config.format == OutputFormat::Text)
+	}
+
+	/// Prints a message about what's going on, and adds it to the log file.
+	fn say(&mut self, message: &str) {
+		if self.prints_text() {
+			println!("{}", message);
+		}
+		self.log_text(message);
+	}
+
+	/// Prints a message in a colour (if colours are being used), and adds it to the log file.
+	fn say_in(&mut self, color: Color, message: &str) {
+		if self.prints_text() {
+			if self.config.color.enabled(&io::stdout()) {
+				println!("{}", color.paint(message));
+			} else {
+				println!("{}", message);
+			}
+		}
+		self.log_text(message);
+	}
+
+	/// Prints a message with more detail than usual, if the verbosity is at least the given level.
+	fn detail(&mut self, level: u8, message: &str) {
+		if self.config.verbosity >= level {
+			self.say_in(Color::Dim, message);
+		}
+	}
+
+	/// Prints a message about something going wrong, and adds it to the log file.
+	fn warn(&mut self, message: &str) {
+		if self.config.color.enabled(&io::stderr()) {
+			eprintln!("{}", Color::Red.paint(message));
+		} else {
+			eprintln!("{}", message);
+		}
+		self.log_text(message);
+	}
+
+	/// Adds a message to the log file, if there is one and it's for messages rather than JSON.
+	fn log_text(&mut self, message: &str) {
+		if self.config.format == OutputFormat::Text {
+			let line = format!("[{}] {}", log::timestamp(self.config.local_time), message);
+			self.log(&line);
+		}
+	}
+
+	/// Prints the event if the output is JSON, and adds it to the log file.
+	///
+	/// Only errors are printed when being quiet (although everything still goes to the log file),
+	/// and checks that didn't find a change are only reported when being verbose.
+	fn print_event(&mut self, event: OutputEvent) {
+		if self.config.format != OutputFormat::Json {
+			return;
+		}
+		if matches!(event, OutputEvent::NoChange { .. }) && self.config.verbosity == 0 {
+			return;
+		}
+		let line = event.to_json();
+		if !self.config.quiet || matches!(event, OutputEvent::Error { .. }) {
+			println!("{}", line);
+		}
+		self.log(&line);
+	}
+
+	/// Adds a line to the log file, if there is one.
+	///
+	/// Problems with the log file are only printed (once until it works again), so that they
+	/// don't end up being logged themselves.
+	fn log(&mut self, line: &str) {
+		let log_file = match &mut self.log_file {
+			Some(log_file) => log_file,
+			None => return,
+		};
+		match log_file.write_line(line) {
+			Ok(()) => self.log_failing = false,
+			Err(e) => {
+				if !self.log_failing {
+					eprintln!(
+						"Unable to write to the log file {}: {}",
+						log_file.path().display(),
+						e
+					);
+				}
+				self.log_failing = true;
+			}
+		}
+	}
+
+	fn report_pruned(
+		&mut self,
+		watch_file: &str,
+		pruned: io::Result<Vec<PathBuf>>,
+	) -> Result<(), WatchError> {
+		match pruned {
+			Ok(pruned) => {
+				for path in pruned {
+					self.say(&format!("Pruned old backup {}", path.display()));
+					self.print_event(OutputEvent::Pruned {
+						file: watch_file,
+						backup: &path,
+					});
+				}
+				Ok(())
+			}
+			Err(source) => self.report_error(WatchError::Prune {
+				path: watch_file.to_owned(),
+				source,
+			}),
+		}
+	}
+
+	/// Works out where the hardlink store for the backups in the location is, which is shared by
+	/// every file when there's an output directory.
+	fn store_for(&self, location: &BackupLocation) -> Store {
+		match &self.config.output_dir {
+			Some(output_dir) => Store::in_dir(output_dir),
+			None => Store::in_dir(&location.backup_dir()),
+		}
+	}
+
+	/// Works out where the target's backups go.
+	///
+	/// Backups placed in an output directory mirror the target's relative path.
+	fn backup_location(&self, target: &WatchTarget) -> BackupLocation {
+		let (dir, file_path) = match &self.config.output_dir {
+			Some(output_dir) => {
+				let relative_path = output_dir.join(&target.relative_path);
+				(backup::parent_dir(&relative_path), relative_path)
+			}
+			None => {
+				let path = target.named_after.as_ref().unwrap_or(&target.path);
+				(backup::parent_dir(Path::new(path)), PathBuf::from(path))
+			}
+		};
+		let name = file_path
+			.file_name()
+			.map_or_else(|| target.path.clone(), |n| n.to_string_lossy().into_owned());
+		BackupLocation {
+			dir,
+			name,
+			template: self.config.name_template.clone(),
+			local_time: self.config.local_time,
+		}
+	}
+}
+
+/// Hashes a watched file, retrying if it's locked.
+///
+/// When symlinks aren't being followed, where a symlink points to is part of the hash too, so that
+/// pointing it somewhere else counts as a change.
+fn hash_contents(config: &WatchConfig, watch_file: &str) -> Result<String, WatchError> {
+	let hash = config
+		.lock_retry
+		.run(|| hash_file(Path::new(watch_file), &config.hash))
+		.map_err(|source| WatchError::Hash {
+			path: watch_file.to_owned(),
+			source,
+		})?;
+	with_link(config, watch_file, hash)
+}
+
+/// Mixes where the watched file points to into the hash of its content, if it's a symlink and
+/// symlinks aren't being followed.
+fn with_link(config: &WatchConfig, watch_file: &str, hash: String) -> Result<String, WatchError> {
+	let link = match link_destination(config, watch_file) {
+		Some(link) => link,
+		None => return Ok(hash),
+	};
+	let linked = format!("{}\0{}", link.to_string_lossy(), hash);
+	config
+		.hash
+		.algorithm
+		.hash_reader(linked.as_bytes())
+		.map_err(|source| WatchError::Hash {
+			path: watch_file.to_owned(),
+			source,
+		})
+}
+
+/// The cheap-to-check metadata of a watched file, if it exists.
+fn file_stats(config: &WatchConfig, watch_file: &str) -> Option<FileStats> {
+	fs::metadata(watch_file).ok().map(|metadata| FileStats {
+		len: metadata.len(),
+		modified: metadata.modified().ok(),
+		link: link_destination(config, watch_file),
+		identity: file_identity(&metadata),
+	})
+}
+
+/// Finds the file in the same directory as the path that's the one with the identity, other than
+/// the one at the path itself.
+fn find_file(path: &Path, identity: FileIdentity) -> Option<String> {
+	let dir = match path.parent() {
+		Some(dir) if dir != Path::new("") => dir,
+		_ => Path::new("."),
+	};
+	fs::read_dir(dir)
+		.ok()?
+		.filter_map(Result::ok)
+		.map(|entry| path.with_file_name(entry.file_name()))
+		.filter(|candidate| candidate != path && !lock::is_lock_file(candidate))
+		.find(|candidate| {
+			fs::metadata(candidate)
+				.ok()
+				.and_then(|metadata| file_identity(&metadata))
+				== Some(identity)
+		})
+		.map(|candidate| candidate.to_string_lossy().into_owned())
+}
+
+/// Which file the metadata is for.
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> Option<FileIdentity> {
+	use std::os::unix::fs::MetadataExt;
+
+	Some(FileIdentity {
+		device: metadata.dev(),
+		inode: metadata.ino(),
+	})
+}
+
+/// Which file the metadata is for, which can't be told outside of Unix without opening the file.
+#[cfg(not(unix))]
+fn file_identity(_metadata: &fs::Metadata) -> Option<FileIdentity> {
+	None
+}
+
+/// The name a backup is uploaded under, which is the path it has inside the output directory (if
+/// it's in one) so that backups of files with the same name don't end up with the same name.
+fn remote_name(config: &WatchConfig, backup_file: &Path) -> String {
+	config
+		.output_dir
+		.as_ref()
+		.and_then(|output_dir| backup_file.strip_prefix(output_dir).ok())
+		.map_or_else(
+			|| {
+				backup_file
+					.file_name()
+					.unwrap_or_default()
+					.to_string_lossy()
+					.into_owned()
+			},
+			|path| path.to_string_lossy().replace('\\', "/"),
+		)
+}
+
+/// Where a watched file points to, if it's a symlink and symlinks aren't being followed.
+fn link_destination(config: &WatchConfig, watch_file: &str) -> Option<PathBuf> {
+	if config.follow_symlinks {
+		return None;
+	}
+	fs::read_link(watch_file).ok()
+}
+
+/// The backup to base a new delta backup on, which is the newest one - unless there have already
+/// been enough delta backups in a row that it's time for a full one.
+///
+/// Anything going wrong while looking (like the newest backup being unreadable) also means a full
+/// backup is made, since that's always safe.
+fn delta_base(location: &BackupLocation, full_every: usize) -> Option<PathBuf> {
+	let newest = location.find_backups().ok()?.pop()?.path;
+	let mut deltas = 0;
+	let mut current = newest.clone();
+	while let Some(base) = delta::base_of(&current).ok()? {
+		deltas += 1;
+		if deltas + 1 >= full_every {
+			return None;
+		}
+		current = base;
+	}
+	(deltas + 1 < full_every).then_some(newest)
+}
+
+/// Formats a backup number, padded so that they line up in directory listings.
+fn format_number(number: u64) -> String {
+	format!("{:04}", number)
+}
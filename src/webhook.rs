@@ -0,0 +1,64 @@
+//! Letting a web service know about each backup by sending it a POST request.
+
+use std::{thread, time::Duration};
+
+/// How many times a request that couldn't be delivered is tried again.
+const RETRIES: u32 = 2;
+
+/// How long to wait before the first retry, which doubles with every retry after that.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a request can take before it's given up on, when that isn't given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A URL to send the details of each backup to, along with any headers to send with it (like
+/// `Authorization`).
+#[derive(Clone)]
+pub struct Webhook {
+	pub url: String,
+	pub headers: Vec<(String, String)>,
+	/// How long each request can take before it's given up on
+	pub timeout: Duration,
+}
+
+impl Webhook {
+	/// Sends the body as JSON, retrying a couple of times if it can't be delivered.
+	///
+	/// Any response other than a success counts as not being delivered.
+	pub fn send(&self, body: &serde_json::Value) -> Result<(), String> {
+		let agent = ureq::AgentBuilder::new().timeout(self.timeout).build();
+		let body = body.to_string();
+		let mut delay = RETRY_DELAY;
+		let mut attempt = 0;
+		loop {
+			let mut request = agent
+				.post(&self.url)
+				.set("Content-Type", "application/json");
+			for (name, value) in &self.headers {
+				request = request.set(name, value);
+			}
+			match request.send_string(&body) {
+				Ok(_) => return Ok(()),
+				Err(e) if attempt >= RETRIES => return Err(e.to_string()),
+				Err(_) => {
+					thread::sleep(delay);
+					delay = delay.saturating_mul(2);
+					attempt += 1;
+				}
+			}
+		}
+	}
+}
+
+/// Parses a header of the form `Name: value`.
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+	match s.split_once(':') {
+		Some((name, value)) if !name.trim().is_empty() => {
+			Ok((name.trim().to_owned(), value.trim().to_owned()))
+		}
+		_ => Err(format!(
+			"invalid header '{}' (expected something like 'Authorization: Bearer <token>')",
+			s
+		)),
+	}
+}